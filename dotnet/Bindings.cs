@@ -1,7 +1,10 @@
 ﻿using System;
+using System.Runtime.CompilerServices;
 using System.Runtime.InteropServices;
+using System.Collections;
 using System.Collections.ObjectModel;
 using System.Management.Automation;
+using System.Security;
 
 namespace NativeHost
 {
@@ -10,135 +13,668 @@ namespace NativeHost
 
     public static class Bindings
     {
+        // Every binding returns one of these result codes. On failure, a serialized
+        // ErrorRecord is written to the error pointer and must be freed by the caller.
+        private const int ResultSuccess = 0;
+        private const int ResultError = 1;
+
+        // Stream identifiers, matching the PowerShellStream enum on the native side.
+        private const int StreamOutput = 0;
+        private const int StreamError = 1;
+        private const int StreamWarning = 2;
+        private const int StreamVerbose = 3;
+        private const int StreamDebug = 4;
+        private const int StreamInformation = 5;
+        private const int StreamProgress = 6;
+
+        // Native callback invoked with each record added to a stream. The record is a
+        // UTF-8 CLIXML string which is only valid for the duration of the call.
+        [UnmanagedFunctionPointer(CallingConvention.Winapi)]
+        private delegate void StreamCallback(IntPtr context, int stream, IntPtr ptrRecord);
+
+        // Native callback invoked once an asynchronous invocation completes. On success
+        // the serialized output objects are passed, otherwise a serialized ErrorRecord.
+        // Both are only valid for the duration of the call.
+        [UnmanagedFunctionPointer(CallingConvention.Winapi)]
+        private delegate void InvokeCallback(IntPtr context, int result, IntPtr ptrObjects, int count, IntPtr ptrError);
+
+        private class StreamSubscription
+        {
+            public StreamCallback Callback;
+            public IntPtr Context;
+        }
+
+        private static readonly ConditionalWeakTable<PowerShell, StreamSubscription> Subscriptions =
+            new ConditionalWeakTable<PowerShell, StreamSubscription>();
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_Create(IntPtr ptrHandle, IntPtr ptrError)
+        {
+            try
+            {
+                // https://stackoverflow.com/a/32108252
+                PowerShell ps = PowerShell.Create();
+                SubscribeStreams(ps);
+                GCHandle gch = GCHandle.Alloc(ps, GCHandleType.Normal);
+                Marshal.WriteIntPtr(ptrHandle, GCHandle.ToIntPtr(gch));
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
         [UnmanagedCallersOnly]
-        public static IntPtr PowerShell_Create()
+        public static int PowerShell_Dispose(IntPtr ptrHandle, IntPtr ptrError)
         {
-            // https://stackoverflow.com/a/32108252
-            PowerShell ps = PowerShell.Create();
-            GCHandle gch = GCHandle.Alloc(ps, GCHandleType.Normal);
-            IntPtr ptrHandle = GCHandle.ToIntPtr(gch);
-            return ptrHandle;
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                Subscriptions.Remove(ps);
+                gch.Free();
+                ps.Dispose();
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static void PowerShell_AddArgument_String(IntPtr ptrHandle, IntPtr ptrArgument)
+        public static int PowerShell_AddArgument_String(IntPtr ptrHandle, IntPtr ptrArgument, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            string argument = Marshal.PtrToStringUTF8(ptrArgument);
-            ps.AddArgument(argument);
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string argument = Marshal.PtrToStringUTF8(ptrArgument);
+                ps.AddArgument(argument);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static void PowerShell_AddParameter_String(IntPtr ptrHandle, IntPtr ptrName, IntPtr ptrValue)
+        public static int PowerShell_AddParameter_String(IntPtr ptrHandle, IntPtr ptrName, IntPtr ptrValue, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            string name = Marshal.PtrToStringUTF8(ptrName);
-            string value = Marshal.PtrToStringUTF8(ptrValue);
-            ps.AddParameter(name, value);
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string name = Marshal.PtrToStringUTF8(ptrName);
+                string value = Marshal.PtrToStringUTF8(ptrValue);
+                ps.AddParameter(name, value);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static void PowerShell_AddParameter_Int(IntPtr ptrHandle, IntPtr ptrName, int value)
+        public static int PowerShell_AddParameter_Int(IntPtr ptrHandle, IntPtr ptrName, int value, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            string name = Marshal.PtrToStringUTF8(ptrName);
-            ps.AddParameter(name, value);
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string name = Marshal.PtrToStringUTF8(ptrName);
+                ps.AddParameter(name, value);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static void PowerShell_AddParameter_Long(IntPtr ptrHandle, IntPtr ptrName, long value)
+        public static int PowerShell_AddParameter_Long(IntPtr ptrHandle, IntPtr ptrName, long value, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            string name = Marshal.PtrToStringUTF8(ptrName);
-            ps.AddParameter(name, value);
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string name = Marshal.PtrToStringUTF8(ptrName);
+                ps.AddParameter(name, value);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static void PowerShell_AddCommand(IntPtr ptrHandle, IntPtr ptrCommand)
+        public static int PowerShell_AddParameter_Switch(IntPtr ptrHandle, IntPtr ptrName, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            string command = Marshal.PtrToStringUTF8(ptrCommand);
-            ps.AddCommand(command);
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string name = Marshal.PtrToStringUTF8(ptrName);
+                ps.AddParameter(name);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static void PowerShell_AddScript(IntPtr ptrHandle, IntPtr ptrScript)
+        public static int PowerShell_AddArgument_CliXml(IntPtr ptrHandle, IntPtr ptrValue, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            string script = Marshal.PtrToStringUTF8(ptrScript);
-            ps.AddScript(script);
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string xml = Marshal.PtrToStringUTF8(ptrValue);
+                ps.AddArgument(PSSerializer.Deserialize(xml));
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static void PowerShell_AddStatement(IntPtr ptrHandle)
+        public static int PowerShell_AddParameter_CliXml(IntPtr ptrHandle, IntPtr ptrName, IntPtr ptrValue, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            ps.AddStatement();
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string name = Marshal.PtrToStringUTF8(ptrName);
+                string xml = Marshal.PtrToStringUTF8(ptrValue);
+                ps.AddParameter(name, PSSerializer.Deserialize(xml));
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        // Script blocks are deserialized as strings, so they are recreated from their text instead.
+        // https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.scriptblock.create
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_AddArgument_ScriptBlock(IntPtr ptrHandle, IntPtr ptrScript, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string script = Marshal.PtrToStringUTF8(ptrScript);
+                ps.AddArgument(ScriptBlock.Create(script));
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_AddParameter_ScriptBlock(IntPtr ptrHandle, IntPtr ptrName, IntPtr ptrScript, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string name = Marshal.PtrToStringUTF8(ptrName);
+                string script = Marshal.PtrToStringUTF8(ptrScript);
+                ps.AddParameter(name, ScriptBlock.Create(script));
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_AddCommand(IntPtr ptrHandle, IntPtr ptrCommand, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string command = Marshal.PtrToStringUTF8(ptrCommand);
+                ps.AddCommand(command);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_AddScript(IntPtr ptrHandle, IntPtr ptrScript, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string script = Marshal.PtrToStringUTF8(ptrScript);
+                ps.AddScript(script);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_AddStatement(IntPtr ptrHandle, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                ps.AddStatement();
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_Invoke(IntPtr ptrHandle, IntPtr ptrObjects, IntPtr ptrCount, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                PSDataCollection<PSObject> results = new PSDataCollection<PSObject>();
+                SubscribeStream(ps, StreamOutput, results);
+                // only terminating errors are thrown, non-terminating ones are left
+                // in the error stream alongside the output
+                ps.Invoke<PSObject, PSObject>(null, results);
+                Marshal.WriteIntPtr(ptrObjects, SerializeObjects(results));
+                Marshal.WriteInt32(ptrCount, results.Count);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_BeginInvoke(IntPtr ptrHandle, IntPtr ptrCallback, IntPtr ptrContext, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                InvokeCallback callback = Marshal.GetDelegateForFunctionPointer<InvokeCallback>(ptrCallback);
+                PSDataCollection<PSObject> results = new PSDataCollection<PSObject>();
+                SubscribeStream(ps, StreamOutput, results);
+                ps.BeginInvoke<PSObject, PSObject>(null, results, null,
+                    asyncResult => EndInvoke(ps, asyncResult, results, callback, ptrContext), null);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        // PowerShell.EndInvoke Method
+        // https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.powershell.endinvoke
+
+        private static void EndInvoke(PowerShell ps, IAsyncResult asyncResult,
+            PSDataCollection<PSObject> results, InvokeCallback callback, IntPtr context)
+        {
+            ErrorRecord errorRecord = null;
+            IntPtr ptrObjects = IntPtr.Zero;
+            int count = 0;
+            try
+            {
+                ps.EndInvoke(asyncResult);
+                ptrObjects = SerializeObjects(results);
+                count = results.Count;
+            }
+            catch (Exception e)
+            {
+                errorRecord = ToErrorRecord(e);
+            }
+
+            if (errorRecord != null)
+            {
+                IntPtr ptrError = Marshal.StringToCoTaskMemUTF8(SerializeError(errorRecord));
+                try
+                {
+                    callback(context, ResultError, IntPtr.Zero, 0, ptrError);
+                }
+                finally
+                {
+                    Marshal.FreeCoTaskMem(ptrError);
+                }
+                return;
+            }
+
+            try
+            {
+                callback(context, ResultSuccess, ptrObjects, count, IntPtr.Zero);
+            }
+            finally
+            {
+                FreeObjects(ptrObjects, count);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static void PowerShell_Invoke(IntPtr ptrHandle)
+        public static int PowerShell_Stop(IntPtr ptrHandle, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            ps.Invoke();
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                ps.Stop();
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static void PowerShell_Clear(IntPtr ptrHandle)
+        public static int PowerShell_Clear(IntPtr ptrHandle, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            ps.Commands.Clear();
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                ps.Commands.Clear();
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static IntPtr PowerShell_ExportToXml(IntPtr ptrHandle, IntPtr ptrName)
+        public static int PowerShell_ExportToXml(IntPtr ptrHandle, IntPtr ptrName, IntPtr ptrResult, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            string name = Marshal.PtrToStringUTF8(ptrName);
-            ps.AddScript(string.Format("[System.Management.Automation.PSSerializer]::Serialize(${0})", name));
-            ps.AddStatement();
-            Collection<PSObject> results = ps.Invoke();
-            string result = results[0].ToString().Trim();
-            ps.Commands.Clear();
-            return Marshal.StringToCoTaskMemUTF8(result);
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string name = Marshal.PtrToStringUTF8(ptrName);
+                ps.AddScript(string.Format("[System.Management.Automation.PSSerializer]::Serialize(${0})", name));
+                ps.AddStatement();
+                Collection<PSObject> results = ps.Invoke();
+                string result = results[0].ToString().Trim();
+                ps.Commands.Clear();
+                Marshal.WriteIntPtr(ptrResult, Marshal.StringToCoTaskMemUTF8(result));
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static IntPtr PowerShell_ExportToJson(IntPtr ptrHandle, IntPtr ptrName)
+        public static int PowerShell_ExportToJson(IntPtr ptrHandle, IntPtr ptrName, IntPtr ptrResult, IntPtr ptrError)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            string name = Marshal.PtrToStringUTF8(ptrName);
-            ps.AddScript(string.Format("${0} | ConvertTo-Json", name));
-            ps.AddStatement();
-            Collection<PSObject> results = ps.Invoke();
-            string result = results[0].ToString().Trim();
-            ps.Commands.Clear();
-            return Marshal.StringToCoTaskMemUTF8(result);
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string name = Marshal.PtrToStringUTF8(ptrName);
+                ps.AddScript(string.Format("${0} | ConvertTo-Json", name));
+                ps.AddStatement();
+                Collection<PSObject> results = ps.Invoke();
+                string result = results[0].ToString().Trim();
+                ps.Commands.Clear();
+                Marshal.WriteIntPtr(ptrResult, Marshal.StringToCoTaskMemUTF8(result));
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
         }
 
         [UnmanagedCallersOnly]
-        public static IntPtr PowerShell_ExportToString(IntPtr ptrHandle, IntPtr ptrName)
+        public static int PowerShell_ExportToString(IntPtr ptrHandle, IntPtr ptrName, IntPtr ptrResult, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                string name = Marshal.PtrToStringUTF8(ptrName);
+                ps.AddScript(string.Format("${0} | Out-String", name));
+                ps.AddStatement();
+                Collection<PSObject> results = ps.Invoke();
+                string result = results[0].ToString().Trim();
+                ps.Commands.Clear();
+                Marshal.WriteIntPtr(ptrResult, Marshal.StringToCoTaskMemUTF8(result));
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_GetStream(IntPtr ptrHandle, int stream, IntPtr ptrObjects, IntPtr ptrCount, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                IList records = GetStream(ps.Streams, stream);
+                Marshal.WriteIntPtr(ptrObjects, SerializeObjects(records));
+                Marshal.WriteInt32(ptrCount, records.Count);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_ClearStreams(IntPtr ptrHandle, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                ps.Streams.ClearStreams();
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        [UnmanagedCallersOnly]
+        public static int PowerShell_SetStreamCallback(IntPtr ptrHandle, IntPtr ptrCallback, IntPtr ptrContext, IntPtr ptrError)
+        {
+            try
+            {
+                GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
+                PowerShell ps = (PowerShell) gch.Target;
+                if (ptrCallback == IntPtr.Zero)
+                {
+                    Subscriptions.Remove(ps);
+                    return ResultSuccess;
+                }
+                StreamSubscription subscription = new StreamSubscription
+                {
+                    Callback = Marshal.GetDelegateForFunctionPointer<StreamCallback>(ptrCallback),
+                    Context = ptrContext,
+                };
+                Subscriptions.AddOrUpdate(ps, subscription);
+                return ResultSuccess;
+            }
+            catch (Exception e)
+            {
+                return SetError(ptrError, e);
+            }
+        }
+
+        // PSDataStreams Class
+        // https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.psdatastreams
+
+        private static IList GetStream(PSDataStreams streams, int stream)
+        {
+            switch (stream)
+            {
+                case StreamError: return streams.Error;
+                case StreamWarning: return streams.Warning;
+                case StreamVerbose: return streams.Verbose;
+                case StreamDebug: return streams.Debug;
+                case StreamInformation: return streams.Information;
+                case StreamProgress: return streams.Progress;
+                default: throw new ArgumentOutOfRangeException(nameof(stream));
+            }
+        }
+
+        // PSDataCollection<T>.DataAdded Event
+        // https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.psdatacollection-1.dataadded
+
+        private static void SubscribeStreams(PowerShell ps)
+        {
+            SubscribeStream(ps, StreamError, ps.Streams.Error);
+            SubscribeStream(ps, StreamWarning, ps.Streams.Warning);
+            SubscribeStream(ps, StreamVerbose, ps.Streams.Verbose);
+            SubscribeStream(ps, StreamDebug, ps.Streams.Debug);
+            SubscribeStream(ps, StreamInformation, ps.Streams.Information);
+            SubscribeStream(ps, StreamProgress, ps.Streams.Progress);
+        }
+
+        private static void SubscribeStream<T>(PowerShell ps, int stream, PSDataCollection<T> records)
+        {
+            records.DataAdded += (sender, e) => OnDataAdded(ps, stream, records, e.Index);
+        }
+
+        private static void OnDataAdded(PowerShell ps, int stream, IList records, int index)
+        {
+            if (!Subscriptions.TryGetValue(ps, out StreamSubscription subscription))
+            {
+                return;
+            }
+            IntPtr ptrRecord = Marshal.StringToCoTaskMemUTF8(PSSerializer.Serialize(records[index]));
+            try
+            {
+                subscription.Callback(subscription.Context, stream, ptrRecord);
+            }
+            finally
+            {
+                Marshal.FreeCoTaskMem(ptrRecord);
+            }
+        }
+
+        // Serializes each object to its own CLIXML document, returning an array of
+        // UTF-8 strings allocated with CoTaskMem, to be freed by the caller.
+        private static IntPtr SerializeObjects(IList objects)
+        {
+            IntPtr ptrObjects = Marshal.AllocCoTaskMem(IntPtr.Size * objects.Count);
+            for (int i = 0; i < objects.Count; i++)
+            {
+                string xml = PSSerializer.Serialize(objects[i]);
+                Marshal.WriteIntPtr(ptrObjects, i * IntPtr.Size, Marshal.StringToCoTaskMemUTF8(xml));
+            }
+            return ptrObjects;
+        }
+
+        private static void FreeObjects(IntPtr ptrObjects, int count)
+        {
+            for (int i = 0; i < count; i++)
+            {
+                Marshal.FreeCoTaskMem(Marshal.ReadIntPtr(ptrObjects, i * IntPtr.Size));
+            }
+            Marshal.FreeCoTaskMem(ptrObjects);
+        }
+
+        // ErrorRecord Class
+        // https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.errorrecord
+
+        private static ErrorRecord ToErrorRecord(Exception e)
+        {
+            if (e is IContainsErrorRecord containsErrorRecord)
+            {
+                return containsErrorRecord.ErrorRecord;
+            }
+            return new ErrorRecord(e, e.GetType().FullName, ErrorCategory.NotSpecified, null);
+        }
+
+        // Serializes an error record, falling back to a hand-built record with only
+        // its message when the record itself cannot be serialized.
+        private static string SerializeError(ErrorRecord errorRecord)
+        {
+            try
+            {
+                return PSSerializer.Serialize(errorRecord);
+            }
+            catch (Exception)
+            {
+            }
+
+            string message;
+            try
+            {
+                message = errorRecord?.ToString() ?? "Unknown error";
+            }
+            catch (Exception)
+            {
+                message = "Unknown error";
+            }
+            return "<Objs Version=\"1.1.0.1\" xmlns=\"http://schemas.microsoft.com/powershell/2004/04\">" +
+                "<Obj RefId=\"0\"><TN RefId=\"0\"><T>System.Management.Automation.ErrorRecord</T>" +
+                "<T>System.Object</T></TN><ToString>" + SecurityElement.Escape(message) +
+                "</ToString></Obj></Objs>";
+        }
+
+        // Called from the catch blocks of [UnmanagedCallersOnly] methods, which
+        // terminate the process if an exception escapes them, so this never throws.
+        private static int SetError(IntPtr ptrError, Exception e)
+        {
+            ErrorRecord errorRecord = null;
+            try
+            {
+                errorRecord = ToErrorRecord(e);
+            }
+            catch (Exception)
+            {
+            }
+            return SetError(ptrError, errorRecord);
+        }
+
+        private static int SetError(IntPtr ptrError, ErrorRecord errorRecord)
         {
-            GCHandle gch = GCHandle.FromIntPtr(ptrHandle);
-            PowerShell ps = (PowerShell) gch.Target;
-            string name = Marshal.PtrToStringUTF8(ptrName);
-            ps.AddScript(string.Format("${0} | Out-String", name));
-            ps.AddStatement();
-            Collection<PSObject> results = ps.Invoke();
-            string result = results[0].ToString().Trim();
-            ps.Commands.Clear();
-            return Marshal.StringToCoTaskMemUTF8(result);
+            try
+            {
+                Marshal.WriteIntPtr(ptrError, Marshal.StringToCoTaskMemUTF8(SerializeError(errorRecord)));
+            }
+            catch (Exception)
+            {
+                // nothing was written, the native side reports an error without a record
+            }
+            return ResultError;
         }
 
         // Marshal Class
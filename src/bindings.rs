@@ -1,84 +1,238 @@
 #![allow(dead_code)]
 
+use crate::cli_xml::{parse_cli_xml, CliObject, CliValue};
 use crate::delegate_loader::{AssemblyDelegateLoader, MethodWithUnknownSignature};
 use crate::error::Error;
-use crate::loader::get_assembly_delegate_loader;
+use crate::future::{invoke_callback, InvokeFuture};
 use crate::pdcstr;
 use crate::pdcstring::{PdCStr, PdCString};
+use crate::records::{
+    DebugRecord, ErrorRecord, InformationRecord, ProgressRecord, VerboseRecord, WarningRecord,
+};
+use crate::runtime::PwshRuntime;
+use std::collections::BTreeMap;
 use std::ffi::{CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 pub type PowerShellHandle = *mut libc::c_void;
 
-pub type FnPowerShellCreate = unsafe extern "system" fn() -> PowerShellHandle;
+pub type FnPowerShellCreate = unsafe extern "system" fn(
+    handle: *mut PowerShellHandle,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
-pub type FnPowerShellAddArgumentString =
-    unsafe extern "system" fn(handle: PowerShellHandle, argument: *const libc::c_char);
+pub type FnPowerShellDispose = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellAddArgumentString = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    argument: *const libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
 pub type FnPowerShellAddParameterString = unsafe extern "system" fn(
     handle: PowerShellHandle,
     name: *const libc::c_char,
     value: *const libc::c_char,
-);
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
-pub type FnPowerShellAddParameterInt =
-    unsafe extern "system" fn(handle: PowerShellHandle, name: *const libc::c_char, value: i32);
+pub type FnPowerShellAddParameterInt = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    name: *const libc::c_char,
+    value: i32,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
-pub type FnPowerShellAddParameterLong =
-    unsafe extern "system" fn(handle: PowerShellHandle, name: *const libc::c_char, value: i64);
+pub type FnPowerShellAddParameterLong = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    name: *const libc::c_char,
+    value: i64,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
-pub type FnPowerShellAddCommand =
-    unsafe extern "system" fn(handle: PowerShellHandle, command: *const libc::c_char);
+pub type FnPowerShellAddParameterSwitch = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    name: *const libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
-pub type FnPowerShellAddScript =
-    unsafe extern "system" fn(handle: PowerShellHandle, script: *const libc::c_char);
+pub type FnPowerShellAddArgumentCliXml = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    value: *const libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
-pub type FnPowerShellAddStatement =
-    unsafe extern "system" fn(handle: PowerShellHandle) -> PowerShellHandle;
+pub type FnPowerShellAddParameterCliXml = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    name: *const libc::c_char,
+    value: *const libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
-pub type FnPowerShellInvoke = unsafe extern "system" fn(handle: PowerShellHandle);
+pub type FnPowerShellAddArgumentScriptBlock = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    script: *const libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
-pub type FnPowerShellClear = unsafe extern "system" fn(handle: PowerShellHandle);
+pub type FnPowerShellAddParameterScriptBlock = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    name: *const libc::c_char,
+    script: *const libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellAddCommand = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    command: *const libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellAddScript = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    script: *const libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellAddStatement = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellInvoke = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    objs: *mut *mut *mut libc::c_char,
+    count: *mut libc::c_int,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellBeginInvoke = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    callback: FnInvokeCallback,
+    context: *mut libc::c_void,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnInvokeCallback = unsafe extern "system" fn(
+    context: *mut libc::c_void,
+    result: libc::c_int,
+    objs: *const *const libc::c_char,
+    count: libc::c_int,
+    error: *const libc::c_char,
+);
+
+pub type FnPowerShellStop = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellClear = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
 pub type FnPowerShellExportToXml = unsafe extern "system" fn(
     handle: PowerShellHandle,
     name: *const libc::c_char,
-) -> *const libc::c_char;
+    result: *mut *mut libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
 pub type FnPowerShellExportToJson = unsafe extern "system" fn(
     handle: PowerShellHandle,
     name: *const libc::c_char,
-) -> *const libc::c_char;
+    result: *mut *mut libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
 
 pub type FnPowerShellExportToString = unsafe extern "system" fn(
     handle: PowerShellHandle,
     name: *const libc::c_char,
-) -> *const libc::c_char;
+    result: *mut *mut libc::c_char,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellGetStream = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    stream: PowerShellStream,
+    objs: *mut *mut *mut libc::c_char,
+    count: *mut libc::c_int,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellClearStreams = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnPowerShellSetStreamCallback = unsafe extern "system" fn(
+    handle: PowerShellHandle,
+    callback: Option<FnStreamCallback>,
+    context: *mut libc::c_void,
+    error: *mut *mut libc::c_char,
+) -> libc::c_int;
+
+pub type FnStreamCallback = unsafe extern "system" fn(
+    context: *mut libc::c_void,
+    stream: PowerShellStream,
+    record: *const libc::c_char,
+);
 
 pub type FnMarshalFreeCoTaskMem = unsafe extern "system" fn(ptr: *mut libc::c_void);
 
-struct Bindings {
+/// The PowerShell data streams records can be read from or subscribed to.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerShellStream {
+    Output = 0,
+    Error = 1,
+    Warning = 2,
+    Verbose = 3,
+    Debug = 4,
+    Information = 5,
+    Progress = 6,
+}
+
+/// Result code returned by every binding on success, any other
+/// value means that a serialized ErrorRecord has been returned.
+pub(crate) const RESULT_SUCCESS: libc::c_int = 0;
+
+pub(crate) struct Bindings {
     create_fn: FnPowerShellCreate,
+    dispose_fn: FnPowerShellDispose,
     add_argument_string_fn: FnPowerShellAddArgumentString,
     add_parameter_string_fn: FnPowerShellAddParameterString,
     add_parameter_int_fn: FnPowerShellAddParameterInt,
     add_parameter_long_fn: FnPowerShellAddParameterLong,
+    add_parameter_switch_fn: FnPowerShellAddParameterSwitch,
+    add_argument_cli_xml_fn: FnPowerShellAddArgumentCliXml,
+    add_parameter_cli_xml_fn: FnPowerShellAddParameterCliXml,
+    add_argument_script_block_fn: FnPowerShellAddArgumentScriptBlock,
+    add_parameter_script_block_fn: FnPowerShellAddParameterScriptBlock,
     add_command_fn: FnPowerShellAddCommand,
     add_script_fn: FnPowerShellAddScript,
     add_statement_fn: FnPowerShellAddStatement,
     invoke_fn: FnPowerShellInvoke,
+    begin_invoke_fn: FnPowerShellBeginInvoke,
+    stop_fn: FnPowerShellStop,
     clear_fn: FnPowerShellClear,
     export_to_xml_fn: FnPowerShellExportToXml,
     export_to_json_fn: FnPowerShellExportToJson,
     export_to_string_fn: FnPowerShellExportToString,
+    get_stream_fn: FnPowerShellGetStream,
+    clear_streams_fn: FnPowerShellClearStreams,
+    set_stream_callback_fn: FnPowerShellSetStreamCallback,
     marshal_free_co_task_mem_fn: FnMarshalFreeCoTaskMem,
 }
 
 impl Bindings {
-    pub fn new() -> Result<Self, Error> {
-        let fn_loader = get_assembly_delegate_loader();
-        Self::new_with_loader(&fn_loader)
-    }
-
     pub fn new_with_loader(fn_loader: &AssemblyDelegateLoader<PdCString>) -> Result<Self, Error> {
         fn get_function_pointer(
             fn_loader: &AssemblyDelegateLoader<PdCString>,
@@ -97,6 +251,14 @@ impl Bindings {
                 )?;
                 unsafe { std::mem::transmute(fn_ptr) }
             },
+            dispose_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_Dispose"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
             add_argument_string_fn: {
                 let fn_ptr = get_function_pointer(
                     fn_loader,
@@ -129,6 +291,46 @@ impl Bindings {
                 )?;
                 unsafe { std::mem::transmute(fn_ptr) }
             },
+            add_parameter_switch_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_AddParameter_Switch"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
+            add_argument_cli_xml_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_AddArgument_CliXml"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
+            add_parameter_cli_xml_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_AddParameter_CliXml"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
+            add_argument_script_block_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_AddArgument_ScriptBlock"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
+            add_parameter_script_block_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_AddParameter_ScriptBlock"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
             add_command_fn: {
                 let fn_ptr = get_function_pointer(
                     fn_loader,
@@ -161,6 +363,22 @@ impl Bindings {
                 )?;
                 unsafe { std::mem::transmute(fn_ptr) }
             },
+            begin_invoke_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_BeginInvoke"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
+            stop_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_Stop"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
             clear_fn: {
                 let fn_ptr = get_function_pointer(
                     fn_loader,
@@ -193,6 +411,30 @@ impl Bindings {
                 )?;
                 unsafe { std::mem::transmute(fn_ptr) }
             },
+            get_stream_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_GetStream"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
+            clear_streams_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_ClearStreams"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
+            set_stream_callback_fn: {
+                let fn_ptr = get_function_pointer(
+                    fn_loader,
+                    pdcstr!("NativeHost.Bindings, Bindings"),
+                    pdcstr!("PowerShell_SetStreamCallback"),
+                )?;
+                unsafe { std::mem::transmute(fn_ptr) }
+            },
             marshal_free_co_task_mem_fn: {
                 let fn_ptr = get_function_pointer(
                     fn_loader,
@@ -204,127 +446,700 @@ impl Bindings {
         };
         Ok(pwsh)
     }
+
+    unsafe fn check_result(
+        &self,
+        result: libc::c_int,
+        error: *mut libc::c_char,
+    ) -> Result<(), Error> {
+        if result == RESULT_SUCCESS {
+            return Ok(());
+        }
+        if error.is_null() {
+            return Err(Error::MissingErrorRecord);
+        }
+        let error_xml = self.take_string(error);
+        Err(Error::PowerShell(Box::new(ErrorRecord::from_cli_xml(
+            &error_xml,
+        )?)))
+    }
+
+    unsafe fn take_string(&self, cstr_ptr: *mut libc::c_char) -> String {
+        let cstr = CStr::from_ptr(cstr_ptr);
+        let rstr = String::from_utf8_lossy(cstr.to_bytes()).to_string();
+        self.marshal_free_co_task_mem(cstr_ptr as *mut libc::c_void);
+        rstr
+    }
+
+    unsafe fn take_string_array(
+        &self,
+        strs_ptr: *mut *mut libc::c_char,
+        count: libc::c_int,
+    ) -> Vec<String> {
+        let strs = (0..count as usize)
+            .map(|i| self.take_string(*strs_ptr.add(i)))
+            .collect();
+        self.marshal_free_co_task_mem(strs_ptr as *mut libc::c_void);
+        strs
+    }
+
+    pub(crate) fn marshal_free_co_task_mem(&self, ptr: *mut libc::c_void) {
+        unsafe {
+            (self.marshal_free_co_task_mem_fn)(ptr);
+        }
+    }
 }
 
-pub struct PowerShell {
-    inner: Bindings,
+/// GCHandle to a managed PowerShell instance, disposed of once the
+/// `PowerShell` and all of its `StopHandle`s have been dropped.
+pub(crate) struct OwnedHandle {
+    inner: &'static Bindings,
     handle: PowerShellHandle,
 }
 
+unsafe impl Send for OwnedHandle {}
+unsafe impl Sync for OwnedHandle {}
+
+impl OwnedHandle {
+    fn raw(&self) -> PowerShellHandle {
+        self.handle
+    }
+}
+
+impl Drop for OwnedHandle {
+    fn drop(&mut self) {
+        unsafe {
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.dispose_fn)(self.handle, &mut error);
+            let _ = self.inner.check_result(result, error);
+        }
+    }
+}
+
+/// Cloneable handle used to stop a running pipeline from any thread.
+#[derive(Clone)]
+pub struct StopHandle {
+    inner: &'static Bindings,
+    handle: Arc<OwnedHandle>,
+}
+
+impl StopHandle {
+    /// Stops the running pipeline, blocking until it has stopped.
+    pub fn stop(&self) -> Result<(), Error> {
+        unsafe {
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.stop_fn)(self.handle.raw(), &mut error);
+            self.inner.check_result(result, error)
+        }
+    }
+}
+
+pub(crate) type Callback<T> = Option<Arc<Mutex<dyn FnMut(Result<&T, &Error>) + Send>>>;
+
+/// Closures registered on a `PowerShell` instance, called from the
+/// managed `DataAdded` event handlers as records are added to a stream.
+#[derive(Default, Clone)]
+pub(crate) struct StreamCallbacks {
+    pub(crate) output: Callback<CliObject>,
+    pub(crate) error: Callback<ErrorRecord>,
+    pub(crate) warning: Callback<WarningRecord>,
+    pub(crate) verbose: Callback<VerboseRecord>,
+    pub(crate) debug: Callback<DebugRecord>,
+    pub(crate) information: Callback<InformationRecord>,
+    pub(crate) progress: Callback<ProgressRecord>,
+}
+
+fn call<T>(callback: &Callback<T>, record: impl FnOnce() -> Result<T, Error>) {
+    if let Some(callback) = callback {
+        let record = record();
+        let mut callback = callback
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        (*callback)(record.as_ref());
+    }
+}
+
+impl StreamCallbacks {
+    fn dispatch(&self, stream: PowerShellStream, xml: &str) {
+        match stream {
+            PowerShellStream::Output => {
+                if self.output.is_some() {
+                    match parse_cli_xml(xml) {
+                        Ok(objs) => {
+                            for obj in objs {
+                                call(&self.output, || Ok(obj));
+                            }
+                        }
+                        Err(err) => call(&self.output, || Err(err)),
+                    }
+                }
+            }
+            PowerShellStream::Error => call(&self.error, || ErrorRecord::from_cli_xml(xml)),
+            PowerShellStream::Warning => call(&self.warning, || WarningRecord::from_cli_xml(xml)),
+            PowerShellStream::Verbose => call(&self.verbose, || VerboseRecord::from_cli_xml(xml)),
+            PowerShellStream::Debug => call(&self.debug, || DebugRecord::from_cli_xml(xml)),
+            PowerShellStream::Information => {
+                call(&self.information, || InformationRecord::from_cli_xml(xml))
+            }
+            PowerShellStream::Progress => {
+                call(&self.progress, || ProgressRecord::from_cli_xml(xml))
+            }
+        }
+    }
+}
+
+// Stream callbacks by the id given to the managed side as the callback context.
+// A DataAdded handler may still call back after the PowerShell was dropped, so
+// the id is looked up here rather than dereferenced as a pointer. Callbacks are
+// cloned out before running, so they may register other callbacks themselves.
+static STREAM_CALLBACKS: Mutex<BTreeMap<usize, StreamCallbacks>> = Mutex::new(BTreeMap::new());
+static NEXT_STREAM_CALLBACKS_ID: AtomicUsize = AtomicUsize::new(1);
+
+pub(crate) fn stream_callbacks() -> MutexGuard<'static, BTreeMap<usize, StreamCallbacks>> {
+    STREAM_CALLBACKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub(crate) unsafe extern "system" fn stream_callback(
+    context: *mut libc::c_void,
+    stream: PowerShellStream,
+    record: *const libc::c_char,
+) {
+    // unwinding into the managed caller would abort the process
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        let callbacks = stream_callbacks().get(&(context as usize)).cloned();
+        if let Some(callbacks) = callbacks {
+            let xml = CStr::from_ptr(record).to_string_lossy();
+            callbacks.dispatch(stream, &xml);
+        }
+    }));
+}
+
+fn reject_nested_script_blocks(value: &CliValue) -> Result<(), Error> {
+    match value {
+        CliValue::CliScriptBlock(_) => Ok(()),
+        value if value.contains_script_block() => Err(Error::NestedScriptBlock),
+        _ => Ok(()),
+    }
+}
+
+pub struct PowerShell {
+    inner: &'static Bindings,
+    handle: Arc<OwnedHandle>,
+    callbacks_id: usize,
+}
+
 impl PowerShell {
-    pub fn new() -> Option<Self> {
-        let bindings = Bindings::new().ok()?;
-        let handle = unsafe { (bindings.create_fn)() };
-        Some(Self {
+    pub fn new() -> Result<Self, Error> {
+        let bindings = PwshRuntime::get()?.bindings();
+        let mut handle: PowerShellHandle = ptr::null_mut();
+        let mut error: *mut libc::c_char = ptr::null_mut();
+        let result = unsafe { (bindings.create_fn)(&mut handle, &mut error) };
+        unsafe { bindings.check_result(result, error)? };
+        Ok(Self {
             inner: bindings,
-            handle: handle,
+            handle: Arc::new(OwnedHandle {
+                inner: bindings,
+                handle,
+            }),
+            callbacks_id: NEXT_STREAM_CALLBACKS_ID.fetch_add(1, Ordering::Relaxed),
         })
     }
 
-    pub fn add_argument_string(&self, argument: &str) {
-        let argument_cstr = CString::new(argument).unwrap();
+    pub fn add_argument_string(&self, argument: &str) -> Result<(), Error> {
+        let argument_cstr = CString::new(argument)?;
         unsafe {
-            (self.inner.add_argument_string_fn)(self.handle, argument_cstr.as_ptr());
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.add_argument_string_fn)(
+                self.handle.raw(),
+                argument_cstr.as_ptr(),
+                &mut error,
+            );
+            self.inner.check_result(result, error)
         }
     }
 
-    pub fn add_parameter_string(&self, name: &str, value: &str) {
-        let name_cstr = CString::new(name).unwrap();
-        let value_cstr = CString::new(value).unwrap();
+    pub fn add_parameter_string(&self, name: &str, value: &str) -> Result<(), Error> {
+        let name_cstr = CString::new(name)?;
+        let value_cstr = CString::new(value)?;
         unsafe {
-            (self.inner.add_parameter_string_fn)(
-                self.handle,
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.add_parameter_string_fn)(
+                self.handle.raw(),
                 name_cstr.as_ptr(),
                 value_cstr.as_ptr(),
+                &mut error,
+            );
+            self.inner.check_result(result, error)
+        }
+    }
+
+    pub fn add_parameter_int(&self, name: &str, value: i32) -> Result<(), Error> {
+        let name_cstr = CString::new(name)?;
+        unsafe {
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.add_parameter_int_fn)(
+                self.handle.raw(),
+                name_cstr.as_ptr(),
+                value,
+                &mut error,
+            );
+            self.inner.check_result(result, error)
+        }
+    }
+
+    pub fn add_parameter_long(&self, name: &str, value: i64) -> Result<(), Error> {
+        let name_cstr = CString::new(name)?;
+        unsafe {
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.add_parameter_long_fn)(
+                self.handle.raw(),
+                name_cstr.as_ptr(),
+                value,
+                &mut error,
+            );
+            self.inner.check_result(result, error)
+        }
+    }
+
+    /// Adds a switch parameter, such as `-Recurse`.
+    pub fn add_switch(&self, name: &str) -> Result<(), Error> {
+        let name_cstr = CString::new(name)?;
+        unsafe {
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.add_parameter_switch_fn)(
+                self.handle.raw(),
+                name_cstr.as_ptr(),
+                &mut error,
             );
+            self.inner.check_result(result, error)
+        }
+    }
+
+    /// Adds each named parameter in turn, like splatting a hashtable.
+    pub fn add_parameters<N, V, I>(&self, parameters: I) -> Result<(), Error>
+    where
+        N: AsRef<str>,
+        V: Into<CliValue>,
+        I: IntoIterator<Item = (N, V)>,
+    {
+        for (name, value) in parameters {
+            self.add_parameter(name.as_ref(), value)?;
         }
+        Ok(())
     }
 
-    pub fn add_parameter_int(&self, name: &str, value: i32) {
-        let name_cstr = CString::new(name).unwrap();
+    /// Adds an argument of any CLIXML type, deserialized with PSSerializer on the managed side.
+    ///
+    /// PSSerializer turns script blocks into strings, so a script block is only
+    /// supported as the argument itself and an error is returned for nested ones.
+    pub fn add_argument(&self, value: impl Into<CliValue>) -> Result<(), Error> {
+        let value = value.into();
+        reject_nested_script_blocks(&value)?;
         unsafe {
-            (self.inner.add_parameter_int_fn)(self.handle, name_cstr.as_ptr(), value);
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = match &value {
+                CliValue::CliScriptBlock(script_block) => {
+                    let script_cstr = CString::new(script_block.value.as_str())?;
+                    (self.inner.add_argument_script_block_fn)(
+                        self.handle.raw(),
+                        script_cstr.as_ptr(),
+                        &mut error,
+                    )
+                }
+                _ => {
+                    let value_cstr = CString::new(value.to_cli_xml())?;
+                    (self.inner.add_argument_cli_xml_fn)(
+                        self.handle.raw(),
+                        value_cstr.as_ptr(),
+                        &mut error,
+                    )
+                }
+            };
+            self.inner.check_result(result, error)
         }
     }
 
-    pub fn add_parameter_long(&self, name: &str, value: i64) {
-        let name_cstr = CString::new(name).unwrap();
+    /// Adds a parameter of any CLIXML type, deserialized with PSSerializer on the managed side.
+    ///
+    /// Like with `add_argument`, script blocks nested in the value are rejected.
+    pub fn add_parameter(&self, name: &str, value: impl Into<CliValue>) -> Result<(), Error> {
+        let name_cstr = CString::new(name)?;
+        let value = value.into();
+        reject_nested_script_blocks(&value)?;
         unsafe {
-            (self.inner.add_parameter_long_fn)(self.handle, name_cstr.as_ptr(), value);
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = match &value {
+                CliValue::CliScriptBlock(script_block) => {
+                    let script_cstr = CString::new(script_block.value.as_str())?;
+                    (self.inner.add_parameter_script_block_fn)(
+                        self.handle.raw(),
+                        name_cstr.as_ptr(),
+                        script_cstr.as_ptr(),
+                        &mut error,
+                    )
+                }
+                _ => {
+                    let value_cstr = CString::new(value.to_cli_xml())?;
+                    (self.inner.add_parameter_cli_xml_fn)(
+                        self.handle.raw(),
+                        name_cstr.as_ptr(),
+                        value_cstr.as_ptr(),
+                        &mut error,
+                    )
+                }
+            };
+            self.inner.check_result(result, error)
         }
     }
 
-    pub fn add_command(&self, command: &str) {
-        let command_cstr = CString::new(command).unwrap();
+    pub fn add_command(&self, command: &str) -> Result<(), Error> {
+        let command_cstr = CString::new(command)?;
         unsafe {
-            (self.inner.add_command_fn)(self.handle, command_cstr.as_ptr());
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result =
+                (self.inner.add_command_fn)(self.handle.raw(), command_cstr.as_ptr(), &mut error);
+            self.inner.check_result(result, error)
         }
     }
 
-    pub fn add_script(&self, script: &str) {
-        let script_cstr = CString::new(script).unwrap();
+    pub fn add_script(&self, script: &str) -> Result<(), Error> {
+        let script_cstr = CString::new(script)?;
         unsafe {
-            (self.inner.add_script_fn)(self.handle, script_cstr.as_ptr());
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result =
+                (self.inner.add_script_fn)(self.handle.raw(), script_cstr.as_ptr(), &mut error);
+            self.inner.check_result(result, error)
         }
     }
 
-    pub fn add_statement(&self) {
+    pub fn add_statement(&self) -> Result<(), Error> {
         unsafe {
-            (self.inner.add_statement_fn)(self.handle);
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.add_statement_fn)(self.handle.raw(), &mut error);
+            self.inner.check_result(result, error)
         }
     }
 
-    pub fn invoke(&self, clear: bool) {
+    /// Invokes the pipeline, returning its output objects. Only a terminating
+    /// error fails the invocation, non-terminating errors such as those from
+    /// `Write-Error` are left in the error stream, see [`PowerShell::errors`].
+    pub fn invoke(&self, clear: bool) -> Result<Vec<CliObject>, Error> {
         unsafe {
-            (self.inner.invoke_fn)(self.handle);
+            let mut objs_ptr: *mut *mut libc::c_char = ptr::null_mut();
+            let mut count: libc::c_int = 0;
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result =
+                (self.inner.invoke_fn)(self.handle.raw(), &mut objs_ptr, &mut count, &mut error);
+            let invoke_result = self
+                .inner
+                .check_result(result, error)
+                .map(|_| self.inner.take_string_array(objs_ptr, count))
+                .and_then(|objs_xml| {
+                    objs_xml
+                        .iter()
+                        .map(|xml| parse_cli_xml(xml))
+                        .collect::<Result<Vec<_>, Error>>()
+                })
+                .map(|objs| objs.into_iter().flatten().collect());
             if clear {
-                (self.inner.clear_fn)(self.handle);
+                self.clear()?;
             }
+            invoke_result
         }
     }
 
-    pub fn clear(&self) {
+    /// Starts the pipeline without blocking, returning a future which
+    /// resolves to the pipeline output once the invocation completes. As with
+    /// [`PowerShell::invoke`], only a terminating error fails the future.
+    ///
+    /// The future keeps the pipeline alive if the `PowerShell` is dropped
+    /// first, but its stream callbacks are no longer called from then on.
+    pub fn invoke_async(&self) -> Result<InvokeFuture, Error> {
+        let future = InvokeFuture::new(self.handle.clone());
+        let context = future.context();
         unsafe {
-            (self.inner.clear_fn)(self.handle);
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.begin_invoke_fn)(
+                self.handle.raw(),
+                invoke_callback,
+                context,
+                &mut error,
+            );
+            if result != RESULT_SUCCESS {
+                InvokeFuture::release_context(context);
+            }
+            self.inner.check_result(result, error)?;
         }
+        Ok(future)
     }
 
-    pub fn export_to_xml(&self, name: &str) -> String {
+    /// Invokes the pipeline, stopping it if it has not completed within `timeout`.
+    pub fn invoke_with_timeout(&self, timeout: Duration) -> Result<Vec<CliObject>, Error> {
+        match self.invoke_async()?.wait_timeout(timeout) {
+            Ok(result) => result,
+            Err(future) => {
+                self.stop()?;
+                let _ = future.wait();
+                Err(Error::Timeout(timeout))
+            }
+        }
+    }
+
+    /// Stops the running pipeline, blocking until it has stopped.
+    pub fn stop(&self) -> Result<(), Error> {
+        self.stop_handle().stop()
+    }
+
+    /// Returns a handle which can stop the running pipeline from another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle {
+            inner: self.inner,
+            handle: self.handle.clone(),
+        }
+    }
+
+    pub fn clear(&self) -> Result<(), Error> {
+        unsafe {
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.clear_fn)(self.handle.raw(), &mut error);
+            self.inner.check_result(result, error)
+        }
+    }
+
+    pub fn export_to_xml(&self, name: &str) -> Result<String, Error> {
+        let name_cstr = CString::new(name)?;
+        unsafe {
+            let mut cstr_ptr: *mut libc::c_char = ptr::null_mut();
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.export_to_xml_fn)(
+                self.handle.raw(),
+                name_cstr.as_ptr(),
+                &mut cstr_ptr,
+                &mut error,
+            );
+            self.inner.check_result(result, error)?;
+            Ok(self.inner.take_string(cstr_ptr))
+        }
+    }
+
+    pub fn export_to_json(&self, name: &str) -> Result<String, Error> {
+        let name_cstr = CString::new(name)?;
+        unsafe {
+            let mut cstr_ptr: *mut libc::c_char = ptr::null_mut();
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.export_to_json_fn)(
+                self.handle.raw(),
+                name_cstr.as_ptr(),
+                &mut cstr_ptr,
+                &mut error,
+            );
+            self.inner.check_result(result, error)?;
+            Ok(self.inner.take_string(cstr_ptr))
+        }
+    }
+
+    pub fn export_to_string(&self, name: &str) -> Result<String, Error> {
+        let name_cstr = CString::new(name)?;
+        unsafe {
+            let mut cstr_ptr: *mut libc::c_char = ptr::null_mut();
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.export_to_string_fn)(
+                self.handle.raw(),
+                name_cstr.as_ptr(),
+                &mut cstr_ptr,
+                &mut error,
+            );
+            self.inner.check_result(result, error)?;
+            Ok(self.inner.take_string(cstr_ptr))
+        }
+    }
+
+    pub fn errors(&self) -> Result<Vec<ErrorRecord>, Error> {
+        let records = self.read_stream(PowerShellStream::Error)?;
+        records
+            .iter()
+            .map(|xml| ErrorRecord::from_cli_xml(xml))
+            .collect()
+    }
+
+    pub fn warnings(&self) -> Result<Vec<WarningRecord>, Error> {
+        let records = self.read_stream(PowerShellStream::Warning)?;
+        records
+            .iter()
+            .map(|xml| WarningRecord::from_cli_xml(xml))
+            .collect()
+    }
+
+    pub fn verbose(&self) -> Result<Vec<VerboseRecord>, Error> {
+        let records = self.read_stream(PowerShellStream::Verbose)?;
+        records
+            .iter()
+            .map(|xml| VerboseRecord::from_cli_xml(xml))
+            .collect()
+    }
+
+    pub fn debug(&self) -> Result<Vec<DebugRecord>, Error> {
+        let records = self.read_stream(PowerShellStream::Debug)?;
+        records
+            .iter()
+            .map(|xml| DebugRecord::from_cli_xml(xml))
+            .collect()
+    }
+
+    pub fn information(&self) -> Result<Vec<InformationRecord>, Error> {
+        let records = self.read_stream(PowerShellStream::Information)?;
+        records
+            .iter()
+            .map(|xml| InformationRecord::from_cli_xml(xml))
+            .collect()
+    }
+
+    pub fn progress(&self) -> Result<Vec<ProgressRecord>, Error> {
+        let records = self.read_stream(PowerShellStream::Progress)?;
+        records
+            .iter()
+            .map(|xml| ProgressRecord::from_cli_xml(xml))
+            .collect()
+    }
+
+    pub fn clear_streams(&self) -> Result<(), Error> {
         unsafe {
-            let name_cstr = CString::new(name).unwrap();
-            let cstr_ptr = (self.inner.export_to_xml_fn)(self.handle, name_cstr.as_ptr());
-            let cstr = CStr::from_ptr(cstr_ptr);
-            let rstr = String::from_utf8_lossy(cstr.to_bytes()).to_string();
-            self.marshal_free_co_task_mem(cstr_ptr as *mut libc::c_void);
-            rstr
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.clear_streams_fn)(self.handle.raw(), &mut error);
+            self.inner.check_result(result, error)
         }
     }
 
-    pub fn export_to_json(&self, name: &str) -> String {
+    /// Calls `callback` with each object written to the output stream during invocation.
+    /// A record that cannot be deserialized is passed to `callback` as an error.
+    pub fn on_output<F>(&self, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Result<&CliObject, &Error>) + Send + 'static,
+    {
+        self.update_callbacks(|callbacks| callbacks.output = Some(Arc::new(Mutex::new(callback))));
+        self.set_stream_callback()
+    }
+
+    /// Calls `callback` with each record written to the error stream during invocation.
+    /// A record that cannot be deserialized is passed to `callback` as an error.
+    pub fn on_error<F>(&self, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Result<&ErrorRecord, &Error>) + Send + 'static,
+    {
+        self.update_callbacks(|callbacks| callbacks.error = Some(Arc::new(Mutex::new(callback))));
+        self.set_stream_callback()
+    }
+
+    /// Calls `callback` with each record written to the warning stream during invocation.
+    /// A record that cannot be deserialized is passed to `callback` as an error.
+    pub fn on_warning<F>(&self, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Result<&WarningRecord, &Error>) + Send + 'static,
+    {
+        self.update_callbacks(|callbacks| callbacks.warning = Some(Arc::new(Mutex::new(callback))));
+        self.set_stream_callback()
+    }
+
+    /// Calls `callback` with each record written to the verbose stream during invocation.
+    /// A record that cannot be deserialized is passed to `callback` as an error.
+    pub fn on_verbose<F>(&self, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Result<&VerboseRecord, &Error>) + Send + 'static,
+    {
+        self.update_callbacks(|callbacks| callbacks.verbose = Some(Arc::new(Mutex::new(callback))));
+        self.set_stream_callback()
+    }
+
+    /// Calls `callback` with each record written to the debug stream during invocation.
+    /// A record that cannot be deserialized is passed to `callback` as an error.
+    pub fn on_debug<F>(&self, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Result<&DebugRecord, &Error>) + Send + 'static,
+    {
+        self.update_callbacks(|callbacks| callbacks.debug = Some(Arc::new(Mutex::new(callback))));
+        self.set_stream_callback()
+    }
+
+    /// Calls `callback` with each record written to the information stream during invocation.
+    /// A record that cannot be deserialized is passed to `callback` as an error.
+    pub fn on_information<F>(&self, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Result<&InformationRecord, &Error>) + Send + 'static,
+    {
+        self.update_callbacks(|callbacks| {
+            callbacks.information = Some(Arc::new(Mutex::new(callback)))
+        });
+        self.set_stream_callback()
+    }
+
+    /// Calls `callback` with each record written to the progress stream during invocation.
+    /// A record that cannot be deserialized is passed to `callback` as an error.
+    pub fn on_progress<F>(&self, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Result<&ProgressRecord, &Error>) + Send + 'static,
+    {
+        self.update_callbacks(|callbacks| {
+            callbacks.progress = Some(Arc::new(Mutex::new(callback)))
+        });
+        self.set_stream_callback()
+    }
+
+    fn update_callbacks(&self, update: impl FnOnce(&mut StreamCallbacks)) {
+        update(stream_callbacks().entry(self.callbacks_id).or_default());
+    }
+
+    fn set_stream_callback(&self) -> Result<(), Error> {
+        let context = self.callbacks_id as *mut libc::c_void;
         unsafe {
-            let name_cstr = CString::new(name).unwrap();
-            let cstr_ptr = (self.inner.export_to_json_fn)(self.handle, name_cstr.as_ptr());
-            let cstr = CStr::from_ptr(cstr_ptr);
-            let rstr = String::from_utf8_lossy(cstr.to_bytes()).to_string();
-            self.marshal_free_co_task_mem(cstr_ptr as *mut libc::c_void);
-            rstr
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.set_stream_callback_fn)(
+                self.handle.raw(),
+                Some(stream_callback),
+                context,
+                &mut error,
+            );
+            self.inner.check_result(result, error)
         }
     }
 
-    pub fn export_to_string(&self, name: &str) -> String {
+    fn clear_stream_callback(&self) -> Result<(), Error> {
         unsafe {
-            let name_cstr = CString::new(name).unwrap();
-            let cstr_ptr = (self.inner.export_to_string_fn)(self.handle, name_cstr.as_ptr());
-            let cstr = CStr::from_ptr(cstr_ptr);
-            let rstr = String::from_utf8_lossy(cstr.to_bytes()).to_string();
-            self.marshal_free_co_task_mem(cstr_ptr as *mut libc::c_void);
-            rstr
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.set_stream_callback_fn)(
+                self.handle.raw(),
+                None,
+                ptr::null_mut(),
+                &mut error,
+            );
+            self.inner.check_result(result, error)
         }
     }
 
-    pub fn marshal_free_co_task_mem(&self, ptr: *mut libc::c_void) {
+    fn read_stream(&self, stream: PowerShellStream) -> Result<Vec<String>, Error> {
         unsafe {
-            (self.inner.marshal_free_co_task_mem_fn)(ptr);
+            let mut objs_ptr: *mut *mut libc::c_char = ptr::null_mut();
+            let mut count: libc::c_int = 0;
+            let mut error: *mut libc::c_char = ptr::null_mut();
+            let result = (self.inner.get_stream_fn)(
+                self.handle.raw(),
+                stream,
+                &mut objs_ptr,
+                &mut count,
+                &mut error,
+            );
+            self.inner.check_result(result, error)?;
+            Ok(self.inner.take_string_array(objs_ptr, count))
         }
     }
 }
+
+impl Drop for PowerShell {
+    fn drop(&mut self) {
+        // a StopHandle or pending InvokeFuture may keep the pipeline running,
+        // callbacks already in flight keep their own reference to the closures
+        let _ = self.clear_stream_callback();
+        stream_callbacks().remove(&self.callbacks_id);
+    }
+}
@@ -1,12 +1,15 @@
 #![allow(dead_code)]
 
-use crate::time::parse_iso8601_duration;
+use crate::error::Error;
 use crate::time::DateTime;
+use crate::time::{format_iso8601_duration, parse_iso8601_duration};
 use decimal::d128;
 use quick_xml::events;
 use quick_xml::events::Event;
 use quick_xml::reader::Reader;
+use std::collections::HashMap;
 use std::time::Duration;
+use thiserror::Error;
 use url::Url;
 use uuid::Uuid;
 
@@ -28,26 +31,272 @@ use uuid::Uuid;
 #[derive(Debug, Clone, Default)]
 pub struct CliObject {
     pub name: Option<String>,
-    pub values: Vec<CliValue>,
     pub ref_id: Option<String>,
     pub type_names: Vec<String>,
+    // <ToString>
     pub string_repr: Option<String>,
+    // primitive value of an enum or wrapped primitive, or the contents of a collection
+    pub value: Option<Box<CliValue>>,
+    // <Props>
+    pub adapted_properties: Vec<CliValue>,
+    // <MS>
+    pub extended_properties: Vec<CliValue>,
 }
 
 impl CliObject {
+    /// Creates an object with `properties` as its extended properties, like a PSCustomObject.
     pub fn new(
         name: Option<&str>,
-        value: Vec<CliValue>,
+        properties: Vec<CliValue>,
         ref_id: Option<&str>,
         type_names: Vec<String>,
         string_repr: Option<&str>,
     ) -> CliObject {
         CliObject {
             name: name.map(|s| s.to_string()),
-            values: value,
             ref_id: ref_id.map(|s| s.to_string()),
-            type_names: type_names,
+            type_names,
             string_repr: string_repr.map(|s| s.to_string()),
+            value: None,
+            adapted_properties: Vec::new(),
+            extended_properties: properties,
+        }
+    }
+
+    /// Returns the adapted properties followed by the extended properties.
+    pub fn properties(&self) -> impl Iterator<Item = &CliValue> {
+        self.adapted_properties
+            .iter()
+            .chain(self.extended_properties.iter())
+    }
+
+    /// Finds a property by name, ignoring case like PowerShell does, in the adapted
+    /// properties first, then the extended properties.
+    pub fn get(&self, name: &str) -> Option<&CliValue> {
+        self.properties()
+            .find(|value| matches!(value.get_name(), Some(n) if eq_ignore_case(n, name)))
+    }
+
+    /// Follows a property path such as `Modules[0].FileName` through nested objects,
+    /// collections and dictionaries. `Value` names the object's own value when it has
+    /// no property of that name, such as the number behind an enum.
+    pub fn query(&self, path: &str) -> Result<&CliValue, CliPathError> {
+        let steps = parse_path(path)?;
+        let mut target = PathTarget::Object(self);
+        let mut value = None;
+        for (step, end) in steps {
+            let next = target.step(step, &path[..end])?;
+            target = PathTarget::from(next);
+            value = Some(next);
+        }
+        value.ok_or_else(|| CliPathError::InvalidPath(path.to_string()))
+    }
+
+    fn query_as<'a, T>(
+        &'a self,
+        path: &str,
+        expected: &'static str,
+        convert: impl FnOnce(&'a CliValue) -> Option<T>,
+    ) -> Result<T, CliPathError> {
+        let value = self.query(path)?;
+        convert(value).ok_or_else(|| CliPathError::WrongType {
+            path: path.to_string(),
+            expected,
+            found: value.kind(),
+        })
+    }
+
+    pub fn get_str(&self, path: &str) -> Result<&str, CliPathError> {
+        self.query_as(path, "a string", |value| value.as_str())
+    }
+
+    pub fn get_bool(&self, path: &str) -> Result<bool, CliPathError> {
+        self.query_as(path, "a boolean", |value| value.as_bool())
+    }
+
+    pub fn get_i32(&self, path: &str) -> Result<i32, CliPathError> {
+        self.query_as(path, "a 32-bit integer", |value| value.as_i32())
+    }
+
+    pub fn get_i64(&self, path: &str) -> Result<i64, CliPathError> {
+        self.query_as(path, "a 64-bit integer", |value| value.as_i64())
+    }
+
+    pub fn get_u64(&self, path: &str) -> Result<u64, CliPathError> {
+        self.query_as(path, "an unsigned 64-bit integer", |value| value.as_u64())
+    }
+
+    pub fn get_double(&self, path: &str) -> Result<f64, CliPathError> {
+        self.query_as(path, "a double", |value| value.as_double())
+    }
+
+    pub fn get_guid(&self, path: &str) -> Result<&Uuid, CliPathError> {
+        self.query_as(path, "a GUID", |value| value.as_guid())
+    }
+
+    pub fn get_datetime(&self, path: &str) -> Result<&DateTime, CliPathError> {
+        self.query_as(path, "a date", |value| value.as_datetime())
+    }
+
+    pub fn get_duration(&self, path: &str) -> Result<&Duration, CliPathError> {
+        self.query_as(path, "a duration", |value| value.as_duration())
+    }
+
+    pub fn get_object(&self, path: &str) -> Result<&CliObject, CliPathError> {
+        self.query_as(path, "an object", |value| value.as_object())
+    }
+
+    /// Returns the elements of a collection, or of an object wrapping a collection.
+    pub fn get_collection(&self, path: &str) -> Result<&Vec<CliValue>, CliPathError> {
+        self.query_as(path, "a collection", |value| match value {
+            CliValue::CliObject(obj) => obj.value.as_deref().and_then(|v| v.as_collection()),
+            value => value.as_collection(),
+        })
+    }
+}
+
+// Property paths, e.g. "Modules[0].FileName"
+
+#[derive(Debug, Error, PartialEq)]
+pub enum CliPathError {
+    #[error("invalid property path `{0}`")]
+    InvalidPath(String),
+    #[error("property `{path}` not found")]
+    MissingProperty { path: String },
+    #[error("index out of range at `{path}`, the collection has {len} elements")]
+    IndexOutOfRange { path: String, len: usize },
+    #[error("`{path}` is {found}, expected {expected}")]
+    WrongType {
+        path: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+}
+
+// PowerShell compares member names with OrdinalIgnoreCase
+fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PathStep<'a> {
+    Property(&'a str),
+    Index(usize),
+}
+
+// Splits a path into steps, each with the end offset of the path leading to it
+fn parse_path(path: &str) -> Result<Vec<(PathStep<'_>, usize)>, CliPathError> {
+    let invalid = || CliPathError::InvalidPath(path.to_string());
+    let mut steps = Vec::new();
+    let mut offset = 0;
+    for (i, segment) in path.split('.').enumerate() {
+        if i > 0 {
+            offset += 1;
+        }
+        let name_len = segment.find('[').unwrap_or(segment.len());
+        let (name, mut indices) = segment.split_at(name_len);
+        if !name.is_empty() {
+            offset += name.len();
+            steps.push((PathStep::Property(name), offset));
+        } else if indices.is_empty() {
+            return Err(invalid());
+        }
+        while !indices.is_empty() {
+            let close = indices.find(']').ok_or_else(invalid)?;
+            let index = indices[1..close].parse().map_err(|_| invalid())?;
+            offset += close + 1;
+            steps.push((PathStep::Index(index), offset));
+            indices = &indices[close + 1..];
+            if !indices.is_empty() && !indices.starts_with('[') {
+                return Err(invalid());
+            }
+        }
+    }
+    Ok(steps)
+}
+
+#[derive(Clone, Copy)]
+enum PathTarget<'a> {
+    Object(&'a CliObject),
+    Value(&'a CliValue),
+}
+
+impl<'a> From<&'a CliValue> for PathTarget<'a> {
+    fn from(value: &'a CliValue) -> Self {
+        match value {
+            CliValue::CliObject(obj) => PathTarget::Object(obj),
+            value => PathTarget::Value(value),
+        }
+    }
+}
+
+impl<'a> PathTarget<'a> {
+    fn kind(&self) -> &'static str {
+        match self {
+            PathTarget::Object(_) => "an object",
+            PathTarget::Value(value) => value.kind(),
+        }
+    }
+
+    // the value an object stands for when it wraps a primitive or collection
+    fn inner(&self) -> Option<&'a CliValue> {
+        match *self {
+            PathTarget::Object(obj) => obj.value.as_deref(),
+            PathTarget::Value(value) => Some(value),
+        }
+    }
+
+    fn step(&self, step: PathStep<'_>, path: &str) -> Result<&'a CliValue, CliPathError> {
+        // the path leading to this target, for reporting its type
+        let parent = path[..path.rfind(['.', '[']).unwrap_or(0)].to_string();
+        match step {
+            PathStep::Property(name) => {
+                if let PathTarget::Object(obj) = *self {
+                    if let Some(value) = obj.get(name) {
+                        return Ok(value);
+                    }
+                    if eq_ignore_case(name, "Value") {
+                        if let Some(value) = obj.value.as_deref() {
+                            return Ok(value);
+                        }
+                    }
+                }
+                match self.inner().and_then(|value| value.as_dictionary()) {
+                    Some(entries) => entries
+                        .iter()
+                        .find(|(key, _)| matches!(key.as_str(), Some(k) if eq_ignore_case(k, name)))
+                        .map(|(_, value)| value)
+                        .ok_or_else(|| CliPathError::MissingProperty {
+                            path: path.to_string(),
+                        }),
+                    None if matches!(self, PathTarget::Object(_)) => {
+                        Err(CliPathError::MissingProperty {
+                            path: path.to_string(),
+                        })
+                    }
+                    None => Err(CliPathError::WrongType {
+                        path: parent,
+                        expected: "an object",
+                        found: self.kind(),
+                    }),
+                }
+            }
+            PathStep::Index(index) => {
+                let values = self
+                    .inner()
+                    .and_then(|value| value.as_collection())
+                    .ok_or_else(|| CliPathError::WrongType {
+                        path: parent,
+                        expected: "a collection",
+                        found: self.kind(),
+                    })?;
+                values.get(index).ok_or(CliPathError::IndexOutOfRange {
+                    path: path.to_string(),
+                    len: values.len(),
+                })
+            }
         }
     }
 }
@@ -620,6 +869,173 @@ impl CliScriptBlock {
     }
 }
 
+// List type (<LST>), serialized as an array of objects
+// Example: <LST><I32>1</I32><I32>2</I32></LST>
+
+#[derive(Debug, Clone, Default)]
+pub struct CliList {
+    pub value: Vec<CliValue>,
+    pub name: Option<String>,
+}
+
+impl CliList {
+    pub fn new(name: Option<&str>, value: Vec<CliValue>) -> CliList {
+        CliList {
+            name: name.map(|s| s.to_string()),
+            value: value,
+        }
+    }
+}
+
+impl<T: Into<CliValue>> From<Vec<T>> for CliList {
+    fn from(value: Vec<T>) -> Self {
+        CliList::new(None, value.into_iter().map(|value| value.into()).collect())
+    }
+}
+
+// Enumerable type (<IE>), for collections that are neither lists, stacks nor queues
+// Example: <IE><S>a</S><S>b</S></IE>
+
+#[derive(Debug, Clone, Default)]
+pub struct CliEnumerable {
+    pub value: Vec<CliValue>,
+    pub name: Option<String>,
+}
+
+impl CliEnumerable {
+    pub fn new(name: Option<&str>, value: Vec<CliValue>) -> CliEnumerable {
+        CliEnumerable {
+            name: name.map(|s| s.to_string()),
+            value,
+        }
+    }
+}
+
+// Stack type (<STK>), elements in popping order
+// Example: <STK><I32>2</I32><I32>1</I32></STK>
+
+#[derive(Debug, Clone, Default)]
+pub struct CliStack {
+    pub value: Vec<CliValue>,
+    pub name: Option<String>,
+}
+
+impl CliStack {
+    pub fn new(name: Option<&str>, value: Vec<CliValue>) -> CliStack {
+        CliStack {
+            name: name.map(|s| s.to_string()),
+            value,
+        }
+    }
+}
+
+// Queue type (<QUE>), elements in dequeuing order
+// Example: <QUE><I32>1</I32><I32>2</I32></QUE>
+
+#[derive(Debug, Clone, Default)]
+pub struct CliQueue {
+    pub value: Vec<CliValue>,
+    pub name: Option<String>,
+}
+
+impl CliQueue {
+    pub fn new(name: Option<&str>, value: Vec<CliValue>) -> CliQueue {
+        CliQueue {
+            name: name.map(|s| s.to_string()),
+            value,
+        }
+    }
+}
+
+// Dictionary type (<DCT>), serialized as a hashtable
+// Example: <DCT><En><S N="Key">Name</S><S N="Value">pwsh</S></En></DCT>
+
+#[derive(Debug, Clone, Default)]
+pub struct CliDictionary {
+    pub value: Vec<(CliValue, CliValue)>,
+    pub name: Option<String>,
+}
+
+impl CliDictionary {
+    pub fn new(name: Option<&str>, value: Vec<(CliValue, CliValue)>) -> CliDictionary {
+        CliDictionary {
+            name: name.map(|s| s.to_string()),
+            value: value,
+        }
+    }
+}
+
+impl<K: Into<CliValue>, V: Into<CliValue>> From<Vec<(K, V)>> for CliDictionary {
+    fn from(value: Vec<(K, V)>) -> Self {
+        let entries = value
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()))
+            .collect();
+        CliDictionary::new(None, entries)
+    }
+}
+
+impl<K: Into<CliValue>, V: Into<CliValue>> From<HashMap<K, V>> for CliDictionary {
+    fn from(value: HashMap<K, V>) -> Self {
+        CliDictionary::from(value.into_iter().collect::<Vec<_>>())
+    }
+}
+
+// Object reference (<Ref>), to an object serialized earlier with the same RefId
+// Example: <Ref N="Parent" RefId="0" />
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CliRef {
+    pub ref_id: String,
+    pub name: Option<String>,
+}
+
+impl CliRef {
+    pub fn new(name: Option<&str>, ref_id: &str) -> CliRef {
+        CliRef {
+            name: name.map(|s| s.to_string()),
+            ref_id: ref_id.to_string(),
+        }
+    }
+
+    /// Looks up the referenced object in the objects the reference was parsed with.
+    pub fn resolve<'a>(&self, objs: &'a [CliObject]) -> Option<&'a CliObject> {
+        find_cli_object(objs, &self.ref_id)
+    }
+}
+
+/// Finds the object with the given RefId, searching nested objects as well.
+pub fn find_cli_object<'a>(objs: &'a [CliObject], ref_id: &str) -> Option<&'a CliObject> {
+    objs.iter().find_map(|obj| find_cli_object_in(obj, ref_id))
+}
+
+fn find_cli_object_in<'a>(obj: &'a CliObject, ref_id: &str) -> Option<&'a CliObject> {
+    if obj.ref_id.as_deref() == Some(ref_id) {
+        return Some(obj);
+    }
+    obj.value
+        .as_deref()
+        .into_iter()
+        .chain(obj.properties())
+        .find_map(|value| find_cli_object_in_value(value, ref_id))
+}
+
+fn find_cli_object_in_value<'a>(value: &'a CliValue, ref_id: &str) -> Option<&'a CliObject> {
+    match value {
+        CliValue::CliObject(obj) => find_cli_object_in(obj, ref_id),
+        CliValue::CliDictionary(dict) => dict
+            .value
+            .iter()
+            .flat_map(|(key, value)| [key, value])
+            .find_map(|value| find_cli_object_in_value(value, ref_id)),
+        _ => value.as_collection().and_then(|values| {
+            values
+                .iter()
+                .find_map(|value| find_cli_object_in_value(value, ref_id))
+        }),
+    }
+}
+
 // Generic CLI XML Value type
 
 #[derive(Debug, Clone)]
@@ -648,9 +1064,71 @@ pub enum CliValue {
     CliVersion(CliVersion),
     CliXmlDocument(CliXmlDocument),
     CliScriptBlock(CliScriptBlock),
+    CliList(CliList),
+    CliEnumerable(CliEnumerable),
+    CliStack(CliStack),
+    CliQueue(CliQueue),
+    CliDictionary(CliDictionary),
+    CliRef(CliRef),
 }
 
 impl CliValue {
+    // describes the type of the value in errors
+    fn kind(&self) -> &'static str {
+        match self {
+            CliValue::CliObject(_) => "an object",
+            CliValue::CliNull(_) => "null",
+            CliValue::CliString(_) => "a string",
+            CliValue::CliChar(_) => "a character",
+            CliValue::CliBool(_) => "a boolean",
+            CliValue::CliDateTime(_) => "a date",
+            CliValue::CliDuration(_) => "a duration",
+            CliValue::CliUInt8(_) => "an unsigned 8-bit integer",
+            CliValue::CliInt8(_) => "an 8-bit integer",
+            CliValue::CliUInt16(_) => "an unsigned 16-bit integer",
+            CliValue::CliInt16(_) => "a 16-bit integer",
+            CliValue::CliUInt32(_) => "an unsigned 32-bit integer",
+            CliValue::CliInt32(_) => "a 32-bit integer",
+            CliValue::CliUInt64(_) => "an unsigned 64-bit integer",
+            CliValue::CliInt64(_) => "a 64-bit integer",
+            CliValue::CliFloat(_) => "a float",
+            CliValue::CliDouble(_) => "a double",
+            CliValue::CliDecimal(_) => "a decimal",
+            CliValue::CliBuffer(_) => "a byte array",
+            CliValue::CliGuid(_) => "a GUID",
+            CliValue::CliUri(_) => "a URI",
+            CliValue::CliVersion(_) => "a version",
+            CliValue::CliXmlDocument(_) => "an XML document",
+            CliValue::CliScriptBlock(_) => "a script block",
+            CliValue::CliList(_) => "a list",
+            CliValue::CliEnumerable(_) => "an enumerable",
+            CliValue::CliStack(_) => "a stack",
+            CliValue::CliQueue(_) => "a queue",
+            CliValue::CliDictionary(_) => "a dictionary",
+            CliValue::CliRef(_) => "a reference",
+        }
+    }
+
+    pub(crate) fn contains_script_block(&self) -> bool {
+        match self {
+            CliValue::CliScriptBlock(_) => true,
+            CliValue::CliObject(obj) => obj
+                .value
+                .as_deref()
+                .into_iter()
+                .chain(obj.properties())
+                .any(|value| value.contains_script_block()),
+            CliValue::CliDictionary(dict) => dict
+                .value
+                .iter()
+                .any(|(key, value)| key.contains_script_block() || value.contains_script_block()),
+            value => matches!(
+                value.as_collection(),
+                Some(values) if values.iter().any(|value| value.contains_script_block())
+            ),
+        }
+    }
+
     pub fn get_name(&self) -> Option<&str> {
         match &*self {
             CliValue::CliObject(prop) => prop.name.as_deref(),
@@ -677,6 +1155,53 @@ impl CliValue {
             CliValue::CliVersion(prop) => prop.name.as_deref(),
             CliValue::CliXmlDocument(prop) => prop.name.as_deref(),
             CliValue::CliScriptBlock(prop) => prop.name.as_deref(),
+            CliValue::CliList(prop) => prop.name.as_deref(),
+            CliValue::CliEnumerable(prop) => prop.name.as_deref(),
+            CliValue::CliStack(prop) => prop.name.as_deref(),
+            CliValue::CliQueue(prop) => prop.name.as_deref(),
+            CliValue::CliDictionary(prop) => prop.name.as_deref(),
+            CliValue::CliRef(prop) => prop.name.as_deref(),
+        }
+    }
+
+    /// Returns the value with its property name set to `name`.
+    pub fn with_name(mut self, name: &str) -> CliValue {
+        self.set_name(Some(name.to_string()));
+        self
+    }
+
+    fn set_name(&mut self, name: Option<String>) {
+        match self {
+            CliValue::CliObject(prop) => prop.name = name,
+            CliValue::CliNull(prop) => prop.name = name,
+            CliValue::CliString(prop) => prop.name = name,
+            CliValue::CliChar(prop) => prop.name = name,
+            CliValue::CliBool(prop) => prop.name = name,
+            CliValue::CliDateTime(prop) => prop.name = name,
+            CliValue::CliDuration(prop) => prop.name = name,
+            CliValue::CliUInt8(prop) => prop.name = name,
+            CliValue::CliInt8(prop) => prop.name = name,
+            CliValue::CliUInt16(prop) => prop.name = name,
+            CliValue::CliInt16(prop) => prop.name = name,
+            CliValue::CliUInt32(prop) => prop.name = name,
+            CliValue::CliInt32(prop) => prop.name = name,
+            CliValue::CliUInt64(prop) => prop.name = name,
+            CliValue::CliInt64(prop) => prop.name = name,
+            CliValue::CliFloat(prop) => prop.name = name,
+            CliValue::CliDouble(prop) => prop.name = name,
+            CliValue::CliDecimal(prop) => prop.name = name,
+            CliValue::CliBuffer(prop) => prop.name = name,
+            CliValue::CliGuid(prop) => prop.name = name,
+            CliValue::CliUri(prop) => prop.name = name,
+            CliValue::CliVersion(prop) => prop.name = name,
+            CliValue::CliXmlDocument(prop) => prop.name = name,
+            CliValue::CliScriptBlock(prop) => prop.name = name,
+            CliValue::CliList(prop) => prop.name = name,
+            CliValue::CliEnumerable(prop) => prop.name = name,
+            CliValue::CliStack(prop) => prop.name = name,
+            CliValue::CliQueue(prop) => prop.name = name,
+            CliValue::CliDictionary(prop) => prop.name = name,
+            CliValue::CliRef(prop) => prop.name = name,
         }
     }
 
@@ -1021,27 +1546,627 @@ impl CliValue {
             _ => None,
         }
     }
+
+    pub fn is_list(&self) -> bool {
+        match *self {
+            CliValue::CliList(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<CliValue>> {
+        match &*self {
+            CliValue::CliList(prop) => Some(&prop.value),
+            _ => None,
+        }
+    }
+
+    pub fn is_enumerable(&self) -> bool {
+        match *self {
+            CliValue::CliEnumerable(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn as_enumerable(&self) -> Option<&Vec<CliValue>> {
+        match &*self {
+            CliValue::CliEnumerable(prop) => Some(&prop.value),
+            _ => None,
+        }
+    }
+
+    pub fn is_stack(&self) -> bool {
+        match *self {
+            CliValue::CliStack(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn as_stack(&self) -> Option<&Vec<CliValue>> {
+        match &*self {
+            CliValue::CliStack(prop) => Some(&prop.value),
+            _ => None,
+        }
+    }
+
+    pub fn is_queue(&self) -> bool {
+        match *self {
+            CliValue::CliQueue(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn as_queue(&self) -> Option<&Vec<CliValue>> {
+        match &*self {
+            CliValue::CliQueue(prop) => Some(&prop.value),
+            _ => None,
+        }
+    }
+
+    /// Returns the elements of a list, enumerable, stack or queue.
+    pub fn as_collection(&self) -> Option<&Vec<CliValue>> {
+        match &*self {
+            CliValue::CliList(prop) => Some(&prop.value),
+            CliValue::CliEnumerable(prop) => Some(&prop.value),
+            CliValue::CliStack(prop) => Some(&prop.value),
+            CliValue::CliQueue(prop) => Some(&prop.value),
+            _ => None,
+        }
+    }
+
+    pub fn is_dictionary(&self) -> bool {
+        match *self {
+            CliValue::CliDictionary(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn as_dictionary(&self) -> Option<&Vec<(CliValue, CliValue)>> {
+        match &*self {
+            CliValue::CliDictionary(prop) => Some(&prop.value),
+            _ => None,
+        }
+    }
+
+    pub fn is_ref(&self) -> bool {
+        match *self {
+            CliValue::CliRef(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn as_ref_id(&self) -> Option<&str> {
+        match &*self {
+            CliValue::CliRef(prop) => Some(&prop.ref_id),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_cli_value_from {
+    ($($cli_type:ident => $value_type:ty),* $(,)?) => {
+        $(
+            impl From<$cli_type> for CliValue {
+                fn from(value: $cli_type) -> Self {
+                    CliValue::$cli_type(value)
+                }
+            }
+
+            impl From<$value_type> for CliValue {
+                fn from(value: $value_type) -> Self {
+                    CliValue::$cli_type($cli_type::new(None, value))
+                }
+            }
+        )*
+    };
+}
+
+impl_cli_value_from! {
+    CliString => &str,
+    CliChar => char,
+    CliBool => bool,
+    CliDateTime => DateTime,
+    CliDuration => Duration,
+    CliUInt8 => u8,
+    CliInt8 => i8,
+    CliUInt16 => u16,
+    CliInt16 => i16,
+    CliUInt32 => u32,
+    CliInt32 => i32,
+    CliUInt64 => u64,
+    CliInt64 => i64,
+    CliFloat => f32,
+    CliDouble => f64,
+    CliDecimal => d128,
+    CliBuffer => Vec<u8>,
+    CliGuid => Uuid,
+    CliUri => Url,
+}
+
+impl From<CliObject> for CliValue {
+    fn from(value: CliObject) -> Self {
+        CliValue::CliObject(value)
+    }
+}
+
+impl From<CliNull> for CliValue {
+    fn from(value: CliNull) -> Self {
+        CliValue::CliNull(value)
+    }
+}
+
+impl From<CliVersion> for CliValue {
+    fn from(value: CliVersion) -> Self {
+        CliValue::CliVersion(value)
+    }
+}
+
+impl From<CliXmlDocument> for CliValue {
+    fn from(value: CliXmlDocument) -> Self {
+        CliValue::CliXmlDocument(value)
+    }
+}
+
+impl From<CliScriptBlock> for CliValue {
+    fn from(value: CliScriptBlock) -> Self {
+        CliValue::CliScriptBlock(value)
+    }
+}
+
+impl From<CliList> for CliValue {
+    fn from(value: CliList) -> Self {
+        CliValue::CliList(value)
+    }
+}
+
+impl From<CliEnumerable> for CliValue {
+    fn from(value: CliEnumerable) -> Self {
+        CliValue::CliEnumerable(value)
+    }
+}
+
+impl From<CliStack> for CliValue {
+    fn from(value: CliStack) -> Self {
+        CliValue::CliStack(value)
+    }
+}
+
+impl From<CliQueue> for CliValue {
+    fn from(value: CliQueue) -> Self {
+        CliValue::CliQueue(value)
+    }
+}
+
+impl From<CliRef> for CliValue {
+    fn from(value: CliRef) -> Self {
+        CliValue::CliRef(value)
+    }
+}
+
+impl From<CliDictionary> for CliValue {
+    fn from(value: CliDictionary) -> Self {
+        CliValue::CliDictionary(value)
+    }
+}
+
+impl<K: Into<CliValue>, V: Into<CliValue>> From<HashMap<K, V>> for CliValue {
+    fn from(value: HashMap<K, V>) -> Self {
+        CliValue::CliDictionary(value.into())
+    }
+}
+
+impl From<String> for CliValue {
+    fn from(value: String) -> Self {
+        CliValue::CliString(CliString::new(None, &value))
+    }
+}
+
+impl From<&[u8]> for CliValue {
+    fn from(value: &[u8]) -> Self {
+        CliValue::CliBuffer(CliBuffer::new(None, value.to_vec()))
+    }
+}
+
+impl<T: Into<CliValue>> From<Option<T>> for CliValue {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => value.into(),
+            None => CliValue::CliNull(CliNull::new(None)),
+        }
+    }
+}
+
+// Serialization to CLIXML, producing documents PSSerializer.Deserialize accepts
+
+const CLI_XML_HEADER: &str =
+    r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04">"#;
+const CLI_XML_FOOTER: &str = "</Objs>";
+
+impl CliValue {
+    pub fn to_cli_xml(&self) -> String {
+        let mut writer = CliXmlWriter::default();
+        writer.xml.push_str(CLI_XML_HEADER);
+        writer.write_value(self);
+        writer.xml.push_str(CLI_XML_FOOTER);
+        writer.xml
+    }
+}
+
+// Encoding of strings, characters invalid in XML are escaped as _xHHHH_
+
+fn encode_cli_xml_string(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => encoded.push_str("&amp;"),
+            '<' => encoded.push_str("&lt;"),
+            '>' => encoded.push_str("&gt;"),
+            '_' if chars.peek() == Some(&'x') => encoded.push_str("_x005F_"),
+            '\u{0}'..='\u{1F}' | '\u{FFFE}' | '\u{FFFF}' => {
+                encoded.push_str(&format!("_x{:04X}_", c as u32))
+            }
+            _ => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+// Decoding of _xHHHH_ escapes, UTF-16 surrogate pairs are escaped as two sequences
+
+fn decode_cli_xml_string(value: &str) -> String {
+    if !value.contains("_x") {
+        return value.to_string();
+    }
+    let mut decoded = String::with_capacity(value.len());
+    let mut units: Vec<u16> = Vec::new();
+    let mut rest = value;
+    while !rest.is_empty() {
+        let unit = rest
+            .get(..7)
+            .filter(|seq| seq.starts_with("_x") && seq.ends_with('_'))
+            .filter(|seq| seq[2..6].bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|seq| u16::from_str_radix(&seq[2..6], 16).ok());
+        match unit {
+            Some(unit) => {
+                units.push(unit);
+                rest = &rest[7..];
+            }
+            None => {
+                decoded.extend(
+                    char::decode_utf16(units.drain(..))
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)),
+                );
+                let c = rest.chars().next().unwrap_or_default();
+                decoded.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    decoded.extend(char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)));
+    decoded
+}
+
+fn format_cli_xml_float(value: String) -> String {
+    match value.as_str() {
+        "inf" => "INF".to_string(),
+        "-inf" => "-INF".to_string(),
+        _ => value,
+    }
+}
+
+#[derive(Default)]
+struct CliXmlWriter {
+    xml: String,
+    ref_id: u32,
+}
+
+impl CliXmlWriter {
+    fn next_ref_id(&mut self) -> u32 {
+        let ref_id = self.ref_id;
+        self.ref_id += 1;
+        ref_id
+    }
+
+    fn write_start(&mut self, tag: &str, name: Option<&str>) {
+        self.xml.push('<');
+        self.xml.push_str(tag);
+        if let Some(name) = name {
+            self.xml.push_str(" N=\"");
+            self.xml.push_str(&quick_xml::escape::escape(name));
+            self.xml.push('"');
+        }
+    }
+
+    fn write_primitive(&mut self, tag: &str, name: Option<&str>, text: &str) {
+        self.write_start(tag, name);
+        self.xml.push('>');
+        self.xml.push_str(text);
+        self.xml.push_str("</");
+        self.xml.push_str(tag);
+        self.xml.push('>');
+    }
+
+    fn write_type_names(&mut self, type_names: &[&str]) {
+        let ref_id = self.next_ref_id();
+        self.xml.push_str(&format!("<TN RefId=\"{}\">", ref_id));
+        for type_name in type_names {
+            self.write_primitive("T", None, &encode_cli_xml_string(type_name));
+        }
+        self.xml.push_str("</TN>");
+    }
+
+    fn write_object(&mut self, obj: &CliObject, name: Option<&str>) {
+        let ref_id = self.next_ref_id();
+        self.write_start("Obj", name);
+        self.xml.push_str(&format!(" RefId=\"{}\">", ref_id));
+        if obj.type_names.is_empty() {
+            // objects without type names are built from Rust as PSCustomObject
+            self.write_type_names(&[
+                "System.Management.Automation.PSCustomObject",
+                "System.Object",
+            ]);
+        } else {
+            let type_names: Vec<&str> = obj.type_names.iter().map(|t| t.as_str()).collect();
+            self.write_type_names(&type_names);
+        }
+        if let Some(string_repr) = obj.string_repr.as_deref() {
+            self.write_primitive("ToString", None, &encode_cli_xml_string(string_repr));
+        }
+        if let Some(value) = obj.value.as_deref() {
+            self.write_value_as(value, None);
+        }
+        self.write_properties("Props", &obj.adapted_properties);
+        self.write_properties("MS", &obj.extended_properties);
+        self.xml.push_str("</Obj>");
+    }
+
+    fn write_properties(&mut self, tag: &str, properties: &[CliValue]) {
+        if properties.is_empty() {
+            return;
+        }
+        self.xml.push_str(&format!("<{}>", tag));
+        for value in properties {
+            self.write_value(value);
+        }
+        self.xml.push_str(&format!("</{}>", tag));
+    }
+
+    fn write_collection(
+        &mut self,
+        tag: &str,
+        type_names: &[&str],
+        values: &[CliValue],
+        name: Option<&str>,
+    ) {
+        let ref_id = self.next_ref_id();
+        self.write_start("Obj", name);
+        self.xml.push_str(&format!(" RefId=\"{}\">", ref_id));
+        self.write_type_names(type_names);
+        self.xml.push_str(&format!("<{}>", tag));
+        for value in values {
+            self.write_value_as(value, None);
+        }
+        self.xml.push_str(&format!("</{}></Obj>", tag));
+    }
+
+    fn write_dictionary(&mut self, dictionary: &CliDictionary, name: Option<&str>) {
+        let ref_id = self.next_ref_id();
+        self.write_start("Obj", name);
+        self.xml.push_str(&format!(" RefId=\"{}\">", ref_id));
+        self.write_type_names(&["System.Collections.Hashtable", "System.Object"]);
+        self.xml.push_str("<DCT>");
+        for (key, value) in &dictionary.value {
+            self.xml.push_str("<En>");
+            self.write_value_as(key, Some("Key"));
+            self.write_value_as(value, Some("Value"));
+            self.xml.push_str("</En>");
+        }
+        self.xml.push_str("</DCT></Obj>");
+    }
+
+    fn write_value(&mut self, value: &CliValue) {
+        self.write_value_as(value, value.get_name())
+    }
+
+    fn write_value_as(&mut self, value: &CliValue, name: Option<&str>) {
+        match value {
+            CliValue::CliObject(obj) => self.write_object(obj, name),
+            CliValue::CliList(list) => self.write_collection(
+                "LST",
+                &["System.Object[]", "System.Array", "System.Object"],
+                &list.value,
+                name,
+            ),
+            CliValue::CliEnumerable(list) => self.write_collection(
+                "IE",
+                &["System.Collections.ArrayList", "System.Object"],
+                &list.value,
+                name,
+            ),
+            CliValue::CliStack(list) => self.write_collection(
+                "STK",
+                &["System.Collections.Stack", "System.Object"],
+                &list.value,
+                name,
+            ),
+            CliValue::CliQueue(list) => self.write_collection(
+                "QUE",
+                &["System.Collections.Queue", "System.Object"],
+                &list.value,
+                name,
+            ),
+            CliValue::CliDictionary(dictionary) => self.write_dictionary(dictionary, name),
+            CliValue::CliRef(prop) => {
+                // written as is, the RefId must match an object written earlier
+                self.write_start("Ref", name);
+                self.xml.push_str(&format!(
+                    " RefId=\"{}\" />",
+                    quick_xml::escape::escape(&prop.ref_id)
+                ));
+            }
+            CliValue::CliNull(_) => {
+                self.write_start("Nil", name);
+                self.xml.push_str(" />");
+            }
+            CliValue::CliString(prop) => {
+                self.write_primitive("S", name, &encode_cli_xml_string(&prop.value))
+            }
+            CliValue::CliChar(prop) => {
+                let mut utf16 = [0u16; 2];
+                match prop.value.encode_utf16(&mut utf16) {
+                    [code] => self.write_primitive("C", name, &code.to_string()),
+                    // a .NET char is a single UTF-16 code unit, so a surrogate pair is a string
+                    _ => self.write_primitive(
+                        "S",
+                        name,
+                        &encode_cli_xml_string(&prop.value.to_string()),
+                    ),
+                }
+            }
+            CliValue::CliBool(prop) => self.write_primitive("B", name, &prop.value.to_string()),
+            CliValue::CliDateTime(prop) => self.write_primitive("DT", name, &prop.value.format()),
+            CliValue::CliDuration(prop) => {
+                self.write_primitive("TS", name, &format_iso8601_duration(&prop.value))
+            }
+            CliValue::CliUInt8(prop) => self.write_primitive("By", name, &prop.value.to_string()),
+            CliValue::CliInt8(prop) => self.write_primitive("SB", name, &prop.value.to_string()),
+            CliValue::CliUInt16(prop) => self.write_primitive("U16", name, &prop.value.to_string()),
+            CliValue::CliInt16(prop) => self.write_primitive("I16", name, &prop.value.to_string()),
+            CliValue::CliUInt32(prop) => self.write_primitive("U32", name, &prop.value.to_string()),
+            CliValue::CliInt32(prop) => self.write_primitive("I32", name, &prop.value.to_string()),
+            CliValue::CliUInt64(prop) => self.write_primitive("U64", name, &prop.value.to_string()),
+            CliValue::CliInt64(prop) => self.write_primitive("I64", name, &prop.value.to_string()),
+            CliValue::CliFloat(prop) => {
+                self.write_primitive("Sg", name, &format_cli_xml_float(prop.value.to_string()))
+            }
+            CliValue::CliDouble(prop) => {
+                self.write_primitive("Db", name, &format_cli_xml_float(prop.value.to_string()))
+            }
+            CliValue::CliDecimal(prop) => self.write_primitive("D", name, &prop.value.to_string()),
+            CliValue::CliBuffer(prop) => {
+                self.write_primitive("BA", name, &base64::encode(&prop.value))
+            }
+            CliValue::CliGuid(prop) => self.write_primitive("G", name, &prop.value.to_string()),
+            CliValue::CliUri(prop) => {
+                self.write_primitive("URI", name, &encode_cli_xml_string(prop.value.as_str()))
+            }
+            CliValue::CliVersion(prop) => {
+                self.write_primitive("Version", name, &encode_cli_xml_string(&prop.value))
+            }
+            CliValue::CliXmlDocument(prop) => {
+                self.write_primitive("XD", name, &encode_cli_xml_string(&prop.value))
+            }
+            CliValue::CliScriptBlock(prop) => {
+                self.write_primitive("SBK", name, &encode_cli_xml_string(&prop.value))
+            }
+        }
+    }
 }
 
 fn try_get_ref_id_attr<B>(reader: &Reader<B>, event: &events::BytesStart) -> Option<String> {
-    let attr = event.try_get_attribute("RefId").ok().unwrap()?;
+    let attr = event.try_get_attribute("RefId").ok().flatten()?;
     let value = attr.decode_and_unescape_value(&reader).ok()?;
     Some(value.to_string())
 }
 
 fn try_get_name_attr<B>(reader: &Reader<B>, event: &events::BytesStart) -> Option<String> {
-    let attr = event.try_get_attribute("N").ok().unwrap()?;
+    let attr = event.try_get_attribute("N").ok().flatten()?;
     let value = attr.decode_and_unescape_value(&reader).ok()?;
     Some(value.to_string())
 }
 
-pub fn parse_cli_xml(cli_xml: &str) -> Vec<CliObject> {
+// An element being parsed that contains values
+enum ParseFrame {
+    Object(CliObject, PropertySet),
+    // <LST>, <IE>, <STK> or <QUE>
+    Collection(&'static [u8], Vec<CliValue>),
+    Dictionary(Vec<(CliValue, CliValue)>),
+    // <En> with its <Key> and <Value>
+    Entry(Option<CliValue>, Option<CliValue>),
+}
+
+// Where values found directly inside an <Obj> belong
+#[derive(Clone, Copy)]
+enum PropertySet {
+    None,
+    Adapted,
+    Extended,
+}
+
+fn current_object(stack: &mut [ParseFrame]) -> Option<&mut CliObject> {
+    match stack.last_mut() {
+        Some(ParseFrame::Object(obj, _)) => Some(obj),
+        _ => None,
+    }
+}
+
+// Adds a value to the innermost element being parsed. Values found outside of any
+// <Obj> are top-level objects of their own, such as strings in pipeline output.
+fn push_value(stack: &mut [ParseFrame], objs: &mut Vec<CliObject>, value: CliValue) {
+    match (stack.last_mut(), value) {
+        (Some(ParseFrame::Object(parent, PropertySet::None)), value) => {
+            parent.value = Some(Box::new(value))
+        }
+        (Some(ParseFrame::Object(parent, PropertySet::Adapted)), value) => {
+            parent.adapted_properties.push(value)
+        }
+        (Some(ParseFrame::Object(parent, PropertySet::Extended)), value) => {
+            parent.extended_properties.push(value)
+        }
+        (Some(ParseFrame::Collection(_, values)), value) => values.push(value),
+        (Some(ParseFrame::Entry(key, entry_value)), mut value) => {
+            // Key and Value are how entries are serialized, not property names
+            let is_key = value.get_name() == Some("Key");
+            value.set_name(None);
+            if is_key {
+                *key = Some(value);
+            } else {
+                *entry_value = Some(value);
+            }
+        }
+        (Some(ParseFrame::Dictionary(_)), _) => {}
+        (None, CliValue::CliObject(obj)) => objs.push(obj),
+        (None, value) => objs.push(CliObject {
+            value: Some(Box::new(value)),
+            ..Default::default()
+        }),
+    }
+}
+
+// Reads the text of an element, with XML entities unescaped and _xHHHH_ decoded
+fn read_text(reader: &mut Reader<&[u8]>, event: &events::BytesStart) -> Result<String, Error> {
+    let raw = reader
+        .read_text(event.name())
+        .map_err(|err| Error::CliXml(reader.buffer_position(), err.to_string()))?;
+    let txt = quick_xml::escape::unescape(&raw)
+        .map_err(|err| Error::CliXml(reader.buffer_position(), err.to_string()))?;
+    Ok(decode_cli_xml_string(&txt))
+}
+
+fn invalid_value<B>(reader: &Reader<B>, event: &events::BytesStart, value: &str) -> Error {
+    let tag = String::from_utf8_lossy(event.name().as_ref()).to_string();
+    Error::CliXml(
+        reader.buffer_position(),
+        format!("invalid <{}> value \"{}\"", tag, value),
+    )
+}
+
+/// Parses a CLIXML document into its top-level objects. Values of unknown
+/// elements are skipped, while malformed XML or primitive values are errors.
+pub fn parse_cli_xml(cli_xml: &str) -> Result<Vec<CliObject>, Error> {
     let mut reader = Reader::from_str(cli_xml);
     reader.expand_empty_elements(true);
     reader.trim_text(true);
 
     let mut objs: Vec<CliObject> = Vec::new();
-    let mut obj = CliObject::default();
+
+    // elements being parsed, from the outermost to the innermost
+    let mut stack: Vec<ParseFrame> = Vec::new();
+
+    // type names by <TN> RefId, for the <TNRef> of later objects of the same type
+    let mut type_names: HashMap<String, Vec<String>> = HashMap::new();
+    let mut type_names_ref: Option<String> = None;
 
     loop {
         let event = reader.read_event();
@@ -1050,199 +2175,279 @@ pub fn parse_cli_xml(cli_xml: &str) -> Vec<CliObject> {
                 match event.name().as_ref() {
                     b"Objs" => {}
                     b"Obj" => {
-                        if let Some(ref_id) = try_get_ref_id_attr(&reader, &event) {
-                            obj.ref_id = Some(ref_id);
-                        }
+                        stack.push(ParseFrame::Object(
+                            CliObject {
+                                name: try_get_name_attr(&reader, &event),
+                                ref_id: try_get_ref_id_attr(&reader, &event),
+                                ..Default::default()
+                            },
+                            PropertySet::None,
+                        ));
                     }
                     b"TN" => {
-                        if let Some(_ref_id) = try_get_ref_id_attr(&reader, &event) {
-                            //println!("TN RefId={}", ref_id);
-                        }
+                        type_names_ref = try_get_ref_id_attr(&reader, &event);
                     }
                     b"T" => {
-                        let txt = reader.read_text(event.name()).unwrap();
-                        obj.type_names.push(txt.to_string());
+                        let txt = read_text(&mut reader, &event)?;
+                        if let Some(obj) = current_object(&mut stack) {
+                            obj.type_names.push(txt);
+                        }
                     }
                     b"TNRef" => {
-                        if let Some(_ref_id) = try_get_ref_id_attr(&reader, &event) {
-                            //println!("TNRef RefId={}", ref_id);
+                        if let Some(ref_id) = try_get_ref_id_attr(&reader, &event) {
+                            if let (Some(obj), Some(names)) =
+                                (current_object(&mut stack), type_names.get(&ref_id))
+                            {
+                                obj.type_names = names.clone();
+                            }
+                        }
+                    }
+                    b"Ref" => {
+                        if let Some(ref_id) = try_get_ref_id_attr(&reader, &event) {
+                            let prop_name = try_get_name_attr(&reader, &event);
+                            let val = CliRef::new(prop_name.as_deref(), &ref_id);
+                            push_value(&mut stack, &mut objs, CliValue::CliRef(val));
                         }
                     }
                     b"ToString" => {
-                        let txt = reader.read_text(event.name()).unwrap();
-                        obj.string_repr = Some(txt.to_string());
+                        let txt = read_text(&mut reader, &event)?;
+                        if let Some(obj) = current_object(&mut stack) {
+                            obj.string_repr = Some(txt);
+                        }
                     }
                     b"Props" => {
                         // Adapted Properties
                         // https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-psrp/173c30d7-b0a6-4aad-9b00-9891c441b0f3
+                        if let Some(ParseFrame::Object(_, set)) = stack.last_mut() {
+                            *set = PropertySet::Adapted;
+                        }
                     }
                     b"MS" => {
                         // Extended Properties
                         // https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-psrp/4cca6d92-4a8e-4406-91cb-0235a98f7d6f
+                        if let Some(ParseFrame::Object(_, set)) = stack.last_mut() {
+                            *set = PropertySet::Extended;
+                        }
                     }
-                    b"LST" => {}
-                    b"IE" => {}
+                    b"LST" => stack.push(ParseFrame::Collection(b"LST", Vec::new())),
+                    b"IE" => stack.push(ParseFrame::Collection(b"IE", Vec::new())),
+                    b"STK" => stack.push(ParseFrame::Collection(b"STK", Vec::new())),
+                    b"QUE" => stack.push(ParseFrame::Collection(b"QUE", Vec::new())),
+                    b"DCT" => stack.push(ParseFrame::Dictionary(Vec::new())),
+                    b"En" => stack.push(ParseFrame::Entry(None, None)),
                     b"B" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliBool::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliBool(val));
+                        let val = CliBool::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliBool(val));
                     }
                     b"S" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
                         let val = CliString::new(prop_name.as_deref(), &txt);
-                        obj.values.push(CliValue::CliString(val));
+                        push_value(&mut stack, &mut objs, CliValue::CliString(val));
                     }
                     b"C" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliChar::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliChar(val));
+                        let val = CliChar::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliChar(val));
                     }
                     b"By" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliUInt8::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliUInt8(val));
+                        let val = CliUInt8::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliUInt8(val));
                     }
                     b"SB" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliInt8::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliInt8(val));
+                        let val = CliInt8::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliInt8(val));
                     }
                     b"U16" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliUInt16::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliUInt16(val));
+                        let val = CliUInt16::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliUInt16(val));
                     }
                     b"I16" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliInt16::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliInt16(val));
+                        let val = CliInt16::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliInt16(val));
                     }
                     b"U32" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliUInt32::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliUInt32(val));
+                        let val = CliUInt32::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliUInt32(val));
                     }
                     b"I32" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliInt32::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliInt32(val));
+                        let val = CliInt32::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliInt32(val));
                     }
                     b"U64" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliUInt64::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliUInt64(val));
+                        let val = CliUInt64::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliUInt64(val));
                     }
                     b"I64" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliInt64::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliInt64(val));
+                        let val = CliInt64::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliInt64(val));
                     }
                     b"DT" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliDateTime::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliDateTime(val));
+                        let val = CliDateTime::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliDateTime(val));
                     }
                     b"TS" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliDuration::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliDuration(val));
+                        let val = CliDuration::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliDuration(val));
                     }
                     b"Sg" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliFloat::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliFloat(val));
+                        let val = CliFloat::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliFloat(val));
                     }
                     b"Db" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliDouble::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliDouble(val));
+                        let val = CliDouble::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliDouble(val));
                     }
                     b"D" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliDecimal::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliDecimal(val));
+                        let val = CliDecimal::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliDecimal(val));
                     }
                     b"BA" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliBuffer::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliBuffer(val));
+                        let val = CliBuffer::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliBuffer(val));
                     }
                     b"G" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliGuid::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliGuid(val));
+                        let val = CliGuid::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliGuid(val));
                     }
                     b"URI" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliUri::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliUri(val));
+                        let val = CliUri::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliUri(val));
                     }
                     b"Version" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliVersion::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliVersion(val));
+                        let val = CliVersion::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliVersion(val));
                     }
                     b"XD" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliXmlDocument::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliXmlDocument(val));
+                        let val = CliXmlDocument::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliXmlDocument(val));
                     }
                     b"SBK" => {
-                        let txt = reader.read_text(event.name()).unwrap();
+                        let txt = read_text(&mut reader, &event)?;
                         let prop_name = try_get_name_attr(&reader, &event);
-                        let val = CliScriptBlock::new_from_str(prop_name.as_deref(), &txt).unwrap();
-                        obj.values.push(CliValue::CliScriptBlock(val));
+                        let val = CliScriptBlock::new_from_str(prop_name.as_deref(), &txt)
+                            .ok_or_else(|| invalid_value(&reader, &event, &txt))?;
+                        push_value(&mut stack, &mut objs, CliValue::CliScriptBlock(val));
                     }
                     b"Nil" => {
                         let prop_name = try_get_name_attr(&reader, &event);
                         let val = CliNull::new(prop_name.as_deref());
-                        obj.values.push(CliValue::CliNull(val));
-                    }
-                    _ => {
-                        let event_name = event.name();
-                        let tag_name = String::from_utf8_lossy(event_name.as_ref());
-                        eprintln!("unsupported: {}", &tag_name);
+                        push_value(&mut stack, &mut objs, CliValue::CliNull(val));
                     }
+                    // unknown elements are skipped, their children are still parsed
+                    _ => {}
                 }
             }
             Ok(Event::End(event)) => match event.name().as_ref() {
+                b"TN" => {
+                    if let (Some(ref_id), Some(obj)) =
+                        (type_names_ref.take(), current_object(&mut stack))
+                    {
+                        type_names.insert(ref_id, obj.type_names.clone());
+                    }
+                }
+                b"Props" | b"MS" => {
+                    if let Some(ParseFrame::Object(_, set)) = stack.last_mut() {
+                        *set = PropertySet::None;
+                    }
+                }
                 b"Obj" => {
-                    objs.push(obj);
-                    obj = CliObject::default();
+                    if let Some(ParseFrame::Object(obj, _)) = stack.pop() {
+                        push_value(&mut stack, &mut objs, CliValue::CliObject(obj));
+                    }
+                }
+                b"LST" | b"IE" | b"STK" | b"QUE" => {
+                    if let Some(ParseFrame::Collection(tag, values)) = stack.pop() {
+                        let value = match tag {
+                            b"LST" => CliValue::CliList(CliList::new(None, values)),
+                            b"IE" => CliValue::CliEnumerable(CliEnumerable::new(None, values)),
+                            b"STK" => CliValue::CliStack(CliStack::new(None, values)),
+                            _ => CliValue::CliQueue(CliQueue::new(None, values)),
+                        };
+                        push_value(&mut stack, &mut objs, value);
+                    }
+                }
+                b"DCT" => {
+                    if let Some(ParseFrame::Dictionary(entries)) = stack.pop() {
+                        let value = CliValue::CliDictionary(CliDictionary::new(None, entries));
+                        push_value(&mut stack, &mut objs, value);
+                    }
+                }
+                b"En" => {
+                    if let Some(ParseFrame::Entry(key, value)) = stack.pop() {
+                        if let Some(ParseFrame::Dictionary(entries)) = stack.last_mut() {
+                            let null = || CliValue::CliNull(CliNull::new(None));
+                            entries.push((key.unwrap_or_else(null), value.unwrap_or_else(null)));
+                        }
+                    }
                 }
                 _ => {}
             },
             Ok(Event::Text(_event)) => {}
             Ok(Event::Eof) => break,
-            Err(event) => panic!(
-                "Error at position {}: {:?}",
-                reader.buffer_position(),
-                event
-            ),
+            Err(err) => return Err(Error::CliXml(reader.buffer_position(), err.to_string())),
             _ => (),
         }
     }
 
-    objs
+    Ok(objs)
 }
@@ -1,13 +1,15 @@
 use crate::delegate_loader::{AssemblyDelegateLoader, DelegateLoader, MethodWithUnknownSignature};
 use crate::error::Error;
-use crate::host_exit_code::HostExitCode;
+use crate::host_exit_code::{HostExitCode, KnownHostExitCode};
 use crate::hostfxr::{
-    GetFunctionPointerFn, Hostfxr, HostfxrDelegateType, Hostfxrhandle,
+    char_t, GetFunctionPointerFn, Hostfxr, HostfxrDelegateType, Hostfxrhandle,
     LoadAssemblyAndGetFunctionPointerFn,
 };
-use crate::pdcstring::PdCStr;
+use crate::pdcstring::{PdCStr, PdCString};
 use core::{mem, ptr};
 use std::borrow::BorrowMut;
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::{marker::PhantomData, ptr::NonNull};
 
 /// A marker struct indicating that the context was initialized for the dotnet command line.
@@ -15,6 +17,11 @@ use std::{marker::PhantomData, ptr::NonNull};
 #[allow(dead_code)]
 pub struct InitializedForCommandLine;
 
+/// A marker struct indicating that the context was initialized with a runtimeconfig.json.
+/// This means that it is not possible to run the application associated with the context.
+#[allow(dead_code)]
+pub struct InitializedForRuntimeConfig;
+
 #[derive(Debug, Clone, Copy)]
 pub struct HostfxrHandle(NonNull<()>);
 
@@ -30,37 +37,74 @@ impl HostfxrHandle {
     }
 }
 
-#[derive(Clone)]
-pub struct HostfxrContext<'a, I> {
+// Closes context handles, implemented by Hostfxr with hostfxr_close
+pub(crate) trait CloseHostfxrHandle {
+    fn close(&self, handle: Hostfxrhandle) -> i32;
+}
+
+impl CloseHostfxrHandle for Hostfxr {
+    fn close(&self, handle: Hostfxrhandle) -> i32 {
+        Hostfxr::close(self, handle)
+    }
+}
+
+// A context handle which is closed exactly once, when dropped
+pub(crate) struct OwnedHostfxrHandle<'a> {
     handle: HostfxrHandle,
+    closer: &'a dyn CloseHostfxrHandle,
+}
+
+impl<'a> OwnedHostfxrHandle<'a> {
+    pub(crate) fn new(handle: HostfxrHandle, closer: &'a dyn CloseHostfxrHandle) -> Self {
+        Self { handle, closer }
+    }
+
+    fn as_raw(&self) -> Hostfxrhandle {
+        self.handle.as_raw()
+    }
+}
+
+impl Drop for OwnedHostfxrHandle<'_> {
+    fn drop(&mut self) {
+        let _ = self.closer.close(self.handle.as_raw());
+    }
+}
+
+/// An initialized hostfxr context, closed when dropped.
+///
+/// Closing a context does not unload the runtime, delegates obtained through
+/// the context remain valid afterwards.
+pub struct HostfxrContext<'a, I> {
+    handle: OwnedHostfxrHandle<'a>,
     hostfxr: &'a Hostfxr,
     context_type: PhantomData<&'a I>,
 }
 
 impl<'a, I> HostfxrContext<'a, I> {
     #[allow(dead_code)]
-    pub fn new(handle: HostfxrHandle, hostfxr: &'a Hostfxr) -> Self {
+    pub(crate) fn new(handle: HostfxrHandle, hostfxr: &'a Hostfxr) -> Self {
         Self {
-            handle,
+            handle: OwnedHostfxrHandle::new(handle, hostfxr),
             hostfxr,
             context_type: PhantomData,
         }
     }
 
     #[allow(dead_code)]
-    pub fn get_runtime_delegate(
+    pub(crate) fn get_runtime_delegate(
         &self,
         delegate_type: HostfxrDelegateType,
     ) -> Result<MethodWithUnknownSignature, Error> {
         let mut delegate = ptr::null::<*mut libc::c_void>() as *mut libc::c_void;
-        let result = unsafe {
+        // the runtime starts on the first delegate request, report why it failed to
+        let (result, message) = self.hostfxr.capture_errors(|| unsafe {
             self.hostfxr.lib.hostfxr_get_runtime_delegate(
                 self.handle.as_raw(),
                 delegate_type,
                 delegate.borrow_mut() as *mut _ as *mut libc::c_void, //Initialise nullptr
             )
-        };
-        HostExitCode::from(result).into_result()?;
+        });
+        HostExitCode::from(result).into_result_with_message(message)?;
         Ok(delegate)
     }
 
@@ -83,7 +127,7 @@ impl<'a, I> HostfxrContext<'a, I> {
     }
 
     #[allow(dead_code)]
-    pub fn get_delegate_loader(&self) -> Result<DelegateLoader, Error> {
+    pub(crate) fn get_delegate_loader(&self) -> Result<DelegateLoader, Error> {
         Ok(DelegateLoader {
             get_load_assembly_and_get_function_pointer: self
                 .get_load_assembly_and_get_function_pointer_delegate()?,
@@ -91,8 +135,96 @@ impl<'a, I> HostfxrContext<'a, I> {
         })
     }
 
+    /// The raw `hostfxr_handle` of the context, valid until the context is dropped.
+    pub fn handle(&self) -> HostfxrHandle {
+        self.handle.handle
+    }
+
+    /// Gets the value of a runtime property, or `None` if it is not set.
+    ///
+    /// Before the runtime starts, the properties are the ones the context was initialized
+    /// with, afterwards they are the properties of the running runtime.
+    pub fn get_runtime_property_value(&self, name: &str) -> Result<Option<String>, Error> {
+        let name = PdCString::from_str(name)?;
+        let mut value: *const char_t = ptr::null();
+        let result = unsafe {
+            self.hostfxr.lib.hostfxr_get_runtime_property_value(
+                self.handle.as_raw(),
+                name.as_ptr(),
+                &mut value,
+            )
+        };
+        match HostExitCode::from(result) {
+            HostExitCode::Known(KnownHostExitCode::HostPropertyNotFound) => Ok(None),
+            code => {
+                code.into_result()?;
+                Ok(Some(
+                    unsafe { PdCStr::from_str_ptr(value) }.to_string_lossy(),
+                ))
+            }
+        }
+    }
+
+    /// Sets a runtime property, or removes it if `value` is `None`.
+    ///
+    /// Properties can only be changed before the runtime starts, that is before a
+    /// delegate is requested or the app is run.
+    pub fn set_runtime_property_value(&self, name: &str, value: Option<&str>) -> Result<(), Error> {
+        let name = PdCString::from_str(name)?;
+        let value = value.map(PdCString::from_str).transpose()?;
+        let result = unsafe {
+            self.hostfxr.lib.hostfxr_set_runtime_property_value(
+                self.handle.as_raw(),
+                name.as_ptr(),
+                value.as_ref().map_or(ptr::null(), |value| value.as_ptr()),
+            )
+        };
+        HostExitCode::from(result).into_result()
+    }
+
+    /// Gets all the runtime properties and their values.
+    pub fn get_runtime_properties(&self) -> Result<HashMap<String, String>, Error> {
+        // query the count first, then retry until the buffers are large enough
+        let mut count: usize = 0;
+        let mut keys: Vec<*const char_t> = Vec::new();
+        let mut values: Vec<*const char_t> = Vec::new();
+        loop {
+            let capacity = count;
+            keys.resize(capacity, ptr::null());
+            values.resize(capacity, ptr::null());
+            let result = unsafe {
+                self.hostfxr.lib.hostfxr_get_runtime_properties(
+                    self.handle.as_raw(),
+                    &mut count,
+                    keys.as_mut_ptr(),
+                    values.as_mut_ptr(),
+                )
+            };
+            let code = HostExitCode::from(result);
+            if code == HostExitCode::Known(KnownHostExitCode::HostApiBufferTooSmall)
+                && count > capacity
+            {
+                continue;
+            }
+            code.into_result()?;
+            break;
+        }
+
+        Ok(keys
+            .iter()
+            .zip(values.iter())
+            .take(count)
+            .map(|(key, value)| unsafe {
+                (
+                    PdCStr::from_str_ptr(*key).to_string_lossy(),
+                    PdCStr::from_str_ptr(*value).to_string_lossy(),
+                )
+            })
+            .collect())
+    }
+
     #[allow(dead_code)]
-    pub fn get_delegate_loader_for_assembly<A: AsRef<PdCStr>>(
+    pub(crate) fn get_delegate_loader_for_assembly<A: AsRef<PdCStr>>(
         &self,
         assembly_path: A,
     ) -> Result<AssemblyDelegateLoader<A>, Error> {
@@ -100,3 +232,17 @@ impl<'a, I> HostfxrContext<'a, I> {
             .map(|loader| AssemblyDelegateLoader::new(loader, assembly_path))
     }
 }
+
+impl<'a> HostfxrContext<'a, InitializedForCommandLine> {
+    /// Runs the application with the command line the context was initialized with,
+    /// and returns its exit code once it exits. The context is closed afterwards.
+    ///
+    /// hostfxr only accepts the command line when the context is initialized, so the
+    /// arguments are passed to `Hostfxr::initialize_for_dotnet_command_line_with_args`
+    /// rather than here.
+    ///
+    /// Runtime properties set on the context are applied before the application starts.
+    pub fn run_app(self) -> HostExitCode {
+        HostExitCode::from(self.hostfxr.run_app(self.handle.as_raw()))
+    }
+}
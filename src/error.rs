@@ -1,5 +1,10 @@
+use crate::host_detect::EnvError;
 use crate::host_exit_code::HostExitCode;
+use crate::pdcstring::NulError;
+use crate::records::ErrorRecord;
 use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
 
 quick_error! {
     /// An error struct encompassing all possible errors of this crate.
@@ -15,6 +20,61 @@ quick_error! {
             display("io error: {}", err)
             source(err)
         }
-        Hostfxr(error_code: HostExitCode)
+        Nul(err: NulError) {
+            from()
+            display("nul error: {}", err)
+            source(err)
+        }
+        CString(err: std::ffi::NulError) {
+            from()
+            display("nul byte in string: {}", err)
+            source(err)
+        }
+        Hostfxr(error_code: HostExitCode, message: String) {
+            display("hostfxr error: {:?}{}{}", error_code, if message.is_empty() { "" } else { ", " }, message)
+        }
+        PwshNotFound(err: EnvError) {
+            from()
+            display("pwsh not found: {}", err)
+            source(err)
+        }
+        HostfxrLoad(path: PathBuf, err: dlopen::Error) {
+            display("failed to load hostfxr from {}: {}", path.display(), err)
+            source(err)
+        }
+        ContextInit(path: PathBuf, error_code: HostExitCode, message: String) {
+            display("failed to initialize hostfxr context for {}: {:?}{}{}", path.display(), error_code, if message.is_empty() { "" } else { ", " }, message)
+        }
+        MissingUnsafeAssemblyLoad(path: PathBuf) {
+            display("PowerShellUnsafeAssemblyLoad not found in {}, PowerShell 7.2 or later is required", path.display())
+        }
+        RuntimeInitialized(path: PathBuf) {
+            display("the PowerShell runtime is already initialized from {}", path.display())
+        }
+        RuntimeFailed(path: PathBuf, message: String) {
+            display("the PowerShell runtime from {} failed to initialize and cannot be retried: {}", path.display(), message)
+        }
+        BindingsLoad(path: PathBuf, err: Box<Error>) {
+            display("failed to load bindings into {}: {}", path.display(), err)
+            source(&**err)
+        }
+        CliXml(position: usize, message: String) {
+            display("invalid CLIXML at position {}: {}", position, message)
+        }
+        PowerShell(err: Box<ErrorRecord>) {
+            display("powershell error: {}", err)
+        }
+        MissingErrorRecord {
+            display("powershell error: the bindings failed without returning an error record")
+        }
+        Panic(message: String) {
+            display("panic while handling pipeline results: {}", message)
+        }
+        NestedScriptBlock {
+            display("script blocks nested in other values are serialized as strings and cannot be passed")
+        }
+        Timeout(timeout: Duration) {
+            display("pipeline stopped after timing out ({:?})", timeout)
+        }
     }
 }
@@ -0,0 +1,147 @@
+use crate::bindings::{OwnedHandle, RESULT_SUCCESS};
+use crate::cli_xml::{parse_cli_xml, CliObject};
+use crate::error::Error;
+use crate::records::ErrorRecord;
+use std::any::Any;
+use std::ffi::CStr;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+#[derive(Default)]
+struct InvokeState {
+    result: Option<Result<Vec<CliObject>, Error>>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct InvokeShared {
+    state: Mutex<InvokeState>,
+    completed: Condvar,
+}
+
+/// Future resolving to the pipeline output of `PowerShell::invoke_async`.
+///
+/// Completion is signaled from the thread running the pipeline, so the
+/// future does not depend on any particular async runtime.
+pub struct InvokeFuture {
+    shared: Arc<InvokeShared>,
+    // keeps the managed instance from being disposed while the pipeline runs
+    _handle: Arc<OwnedHandle>,
+}
+
+impl InvokeFuture {
+    pub(crate) fn new(handle: Arc<OwnedHandle>) -> Self {
+        Self {
+            shared: Arc::default(),
+            _handle: handle,
+        }
+    }
+
+    /// Returns the context pointer to pass to the managed side, holding a
+    /// reference to the shared state until `invoke_callback` consumes it.
+    pub(crate) fn context(&self) -> *mut libc::c_void {
+        Arc::into_raw(self.shared.clone()) as *mut libc::c_void
+    }
+
+    /// Releases the reference returned by `context` when the invocation
+    /// could not be started and the callback will never be called.
+    pub(crate) unsafe fn release_context(context: *mut libc::c_void) {
+        drop(Arc::from_raw(context as *const InvokeShared));
+    }
+
+    /// Blocks the current thread until the invocation completes.
+    pub(crate) fn wait(self) -> Result<Vec<CliObject>, Error> {
+        let state = self.shared.state.lock().unwrap();
+        let mut state = self
+            .shared
+            .completed
+            .wait_while(state, |state| state.result.is_none())
+            .unwrap();
+        state.result.take().unwrap()
+    }
+
+    /// Blocks the current thread until the invocation completes or `timeout`
+    /// elapses, in which case the still pending future is handed back.
+    pub(crate) fn wait_timeout(
+        self,
+        timeout: Duration,
+    ) -> Result<Result<Vec<CliObject>, Error>, Self> {
+        let result = {
+            let state = self.shared.state.lock().unwrap();
+            let (mut state, _) = self
+                .shared
+                .completed
+                .wait_timeout_while(state, timeout, |state| state.result.is_none())
+                .unwrap();
+            state.result.take()
+        };
+        result.ok_or(self)
+    }
+}
+
+impl Future for InvokeFuture {
+    type Output = Result<Vec<CliObject>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub(crate) unsafe extern "system" fn invoke_callback(
+    context: *mut libc::c_void,
+    result: libc::c_int,
+    objs: *const *const libc::c_char,
+    count: libc::c_int,
+    error: *const libc::c_char,
+) {
+    let shared = Arc::from_raw(context as *const InvokeShared);
+    // unwinding into the managed caller would abort the process
+    let output = panic::catch_unwind(AssertUnwindSafe(|| {
+        if result == RESULT_SUCCESS {
+            (0..count.max(0) as usize)
+                .map(|i| parse_cli_xml(&CStr::from_ptr(*objs.add(i)).to_string_lossy()))
+                .collect::<Result<Vec<_>, Error>>()
+                .map(|objs| objs.into_iter().flatten().collect())
+        } else {
+            if error.is_null() {
+                return Err(Error::MissingErrorRecord);
+            }
+            let error_xml = CStr::from_ptr(error).to_string_lossy();
+            Err(Error::PowerShell(Box::new(ErrorRecord::from_cli_xml(
+                &error_xml,
+            )?)))
+        }
+    }))
+    .unwrap_or_else(|payload| Err(Error::Panic(panic_message(&*payload))));
+    let waker = {
+        let mut state = shared
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.result = Some(output);
+        state.waker.take()
+    };
+    shared.completed.notify_all();
+    if let Some(waker) = waker {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| waker.wake()));
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
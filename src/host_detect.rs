@@ -1,5 +1,9 @@
+use std::env;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use thiserror::Error;
 
 #[allow(dead_code)]
@@ -9,25 +13,392 @@ pub enum EnvError {
     UndefOrUnset,
     #[error("PowerShell install dir not found in PATH")]
     Missing,
+    #[error("no PowerShell install dir matches the requested constraints, tried {0:?}")]
+    NoMatchingInstall(Vec<PathBuf>),
 }
 
-pub fn find_pwsh_exe() -> Option<PathBuf> {
-    if let Ok(pwsh_exe) = which::which("pwsh") {
-        if let Ok(pwsh_link) = fs::read_link(&pwsh_exe) {
-            return Some(pwsh_link);
+/// A PowerShell version, without any prerelease label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PwshVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl PwshVersion {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for PwshVersion {
+    type Err = std::num::ParseIntError;
+
+    // "7.4.0", "7.5.0-preview.3" or "7.4"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split(['-', '+']).next().unwrap_or(s);
+        let mut parts = s.splitn(3, '.');
+        let major = parts.next().unwrap_or("").parse()?;
+        let minor = parts.next().map_or(Ok(0), str::parse)?;
+        let patch = parts.next().map_or(Ok(0), str::parse)?;
+        Ok(Self::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for PwshVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Returns true if the directory contains the files needed to host PowerShell.
+pub fn is_pwsh_dir(pwsh_dir: &Path) -> bool {
+    pwsh_dir.join("pwsh.dll").is_file()
+        && pwsh_dir.join("System.Management.Automation.dll").is_file()
+}
+
+/// Reads the PowerShell version of an install dir from its pwsh.deps.json.
+pub fn read_pwsh_version(pwsh_dir: &Path) -> Option<PwshVersion> {
+    read_pwsh_version_label(pwsh_dir)?.parse().ok()
+}
+
+// Full version string such as "7.5.0-preview.3", from pwsh.deps.json or
+// the product version of System.Management.Automation.dll.
+fn read_pwsh_version_label(pwsh_dir: &Path) -> Option<String> {
+    read_deps_json_version(pwsh_dir).or_else(|| read_product_version(pwsh_dir))
+}
+
+fn read_deps_json_version(pwsh_dir: &Path) -> Option<String> {
+    // the pwsh package is listed under "targets" and "libraries" as "pwsh/<version>"
+    let deps_json = fs::read_to_string(pwsh_dir.join("pwsh.deps.json")).ok()?;
+    let start = deps_json.find("\"pwsh/")? + "\"pwsh/".len();
+    let end = deps_json[start..].find('"')? + start;
+    Some(deps_json[start..end].to_string())
+}
+
+fn read_product_version(pwsh_dir: &Path) -> Option<String> {
+    let path = pwsh_dir.join("System.Management.Automation.dll");
+    read_binary_product_version(&mut fs::File::open(path).ok()?)
+}
+
+// The VS_VERSION_INFO resource is in the .rsrc section of the PE file, which is
+// read alone rather than the whole assembly.
+pub(crate) fn read_binary_product_version<R: Read + Seek>(binary: &mut R) -> Option<String> {
+    let u16_at = |bytes: &[u8], offset: usize| {
+        bytes
+            .get(offset..offset + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+    };
+    let u32_at = |bytes: &[u8], offset: usize| {
+        bytes
+            .get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let mut dos_header = [0u8; 64];
+    binary.read_exact(&mut dos_header).ok()?;
+    if &dos_header[0..2] != b"MZ" {
+        return None;
+    }
+    // "PE\0\0" signature and COFF header, followed by the optional header
+    let pe_offset = u32_at(&dos_header, 0x3c)?;
+    let mut pe_header = [0u8; 24];
+    binary.seek(SeekFrom::Start(pe_offset.into())).ok()?;
+    binary.read_exact(&mut pe_header).ok()?;
+    if &pe_header[0..4] != b"PE\0\0" {
+        return None;
+    }
+    let section_count = u16_at(&pe_header, 6)?;
+    let optional_header_size = u16_at(&pe_header, 20)?;
+    binary
+        .seek(SeekFrom::Current(optional_header_size.into()))
+        .ok()?;
+
+    // section headers: Name, VirtualSize, VirtualAddress, SizeOfRawData, PointerToRawData, ...
+    let mut section_headers = Vec::with_capacity(section_count as usize * 40);
+    binary
+        .take(section_count as u64 * 40)
+        .read_to_end(&mut section_headers)
+        .ok()?;
+    let rsrc = section_headers
+        .chunks_exact(40)
+        .find(|section| section.starts_with(b".rsrc\0"))?;
+    let rsrc_size = u32_at(rsrc, 16)?;
+    let rsrc_offset = u32_at(rsrc, 20)?;
+    let mut resources = Vec::with_capacity(rsrc_size as usize);
+    binary.seek(SeekFrom::Start(rsrc_offset.into())).ok()?;
+    binary
+        .take(rsrc_size.into())
+        .read_to_end(&mut resources)
+        .ok()?;
+
+    // VS_VERSION_INFO string table, the UTF-16 "ProductVersion" key is followed by
+    // padding and then the value, like "7.4.0 SHA: 1234abcd"
+    let utf16: Vec<u16> = resources
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    let key: Vec<u16> = "ProductVersion\0".encode_utf16().collect();
+    let start = utf16.windows(key.len()).position(|w| w == key.as_slice())? + key.len();
+    let value: Vec<u16> = utf16[start..]
+        .iter()
+        .skip_while(|c| **c == 0)
+        .take_while(|c| **c != 0)
+        .copied()
+        .collect();
+    let value = String::from_utf16(&value).ok()?;
+    value.split_whitespace().next().map(str::to_string)
+}
+
+/// The processor architecture of a PowerShell installation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PwshArch {
+    X86,
+    X64,
+    Arm,
+    Arm64,
+}
+
+/// A PowerShell installation found on the system.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PwshInstall {
+    /// The installation directory, containing pwsh.dll.
+    pub path: PathBuf,
+    pub version: Option<PwshVersion>,
+    /// True for preview and release candidate builds.
+    pub preview: bool,
+    /// The architecture of the native libraries, unknown for platform-neutral installs.
+    pub arch: Option<PwshArch>,
+}
+
+impl PwshInstall {
+    /// Reads the installation in a directory, or None if it is not a PowerShell install dir.
+    pub fn from_dir(pwsh_dir: &Path) -> Option<Self> {
+        if !is_pwsh_dir(pwsh_dir) {
+            return None;
+        }
+        let label = read_pwsh_version_label(pwsh_dir);
+        Some(Self {
+            path: pwsh_dir.to_path_buf(),
+            version: label.as_deref().and_then(|label| label.parse().ok()),
+            preview: matches!(label, Some(label) if label.contains('-')),
+            arch: read_pwsh_arch(pwsh_dir),
+        })
+    }
+}
+
+// The architecture of the hostfxr library shipped with self-contained installs.
+fn read_pwsh_arch(pwsh_dir: &Path) -> Option<PwshArch> {
+    let names = ["hostfxr.dll", "libhostfxr.so", "libhostfxr.dylib"];
+    let path = names
+        .iter()
+        .map(|name| pwsh_dir.join(name))
+        .find(|path| path.is_file())?;
+    read_binary_arch(&mut fs::File::open(path).ok()?)
+}
+
+pub(crate) fn read_binary_arch<R: Read + Seek>(binary: &mut R) -> Option<PwshArch> {
+    // long enough for the ELF e_machine and the PE e_lfanew fields
+    let mut header = Vec::with_capacity(64);
+    binary.take(64).read_to_end(&mut header).ok()?;
+    let u16_at = |header: &[u8], offset: usize| {
+        header
+            .get(offset..offset + 2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+    };
+    let u32_at = |header: &[u8], offset: usize| {
+        header
+            .get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    match header.get(0..4)? {
+        // ELF, e_machine
+        [0x7f, b'E', b'L', b'F'] => match u16_at(&header, 18)? {
+            0x03 => Some(PwshArch::X86),
+            0x3e => Some(PwshArch::X64),
+            0x28 => Some(PwshArch::Arm),
+            0xb7 => Some(PwshArch::Arm64),
+            _ => None,
+        },
+        // Mach-O 64-bit or 32-bit, cputype
+        [0xcf, 0xfa, 0xed, 0xfe] | [0xce, 0xfa, 0xed, 0xfe] => match u32_at(&header, 4)? {
+            0x0000_0007 => Some(PwshArch::X86),
+            0x0100_0007 => Some(PwshArch::X64),
+            0x0000_000c => Some(PwshArch::Arm),
+            0x0100_000c => Some(PwshArch::Arm64),
+            _ => None,
+        },
+        // PE, Machine field of the COFF header following the "PE\0\0" signature,
+        // found at the offset stored in e_lfanew, which may be anywhere in the file
+        [b'M', b'Z', _, _] => {
+            let pe_offset = u32_at(&header, 0x3c)?;
+            let mut pe_header = [0u8; 6];
+            binary.seek(SeekFrom::Start(pe_offset.into())).ok()?;
+            binary.read_exact(&mut pe_header).ok()?;
+            if &pe_header[0..4] != b"PE\0\0" {
+                return None;
+            }
+            match u16_at(&pe_header, 4)? {
+                0x014c => Some(PwshArch::X86),
+                0x8664 => Some(PwshArch::X64),
+                0x01c4 => Some(PwshArch::Arm),
+                0xaa64 => Some(PwshArch::Arm64),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Finds the PowerShell installations on the system.
+///
+/// Searches `PATH`, the usual per-platform install locations, snap packages and
+/// dotnet global tools. Installs reachable from several places are listed once.
+pub fn find_pwsh_installs() -> Vec<PwshInstall> {
+    read_pwsh_installs(pwsh_install_candidates())
+}
+
+// Reads the installs among candidate directories, skipping directories that are the
+// same once canonicalized, such as a symlinked PATH entry and the install it points to.
+pub(crate) fn read_pwsh_installs(candidates: Vec<PathBuf>) -> Vec<PwshInstall> {
+    let mut installs: Vec<PwshInstall> = Vec::new();
+    let mut seen: Vec<PathBuf> = Vec::new();
+    for pwsh_dir in candidates {
+        let key = fs::canonicalize(&pwsh_dir).unwrap_or_else(|_| pwsh_dir.clone());
+        if seen.contains(&key) {
+            continue;
+        }
+        if let Some(install) = PwshInstall::from_dir(&pwsh_dir) {
+            seen.push(key);
+            installs.push(install);
+        }
+    }
+    installs
+}
+
+fn pwsh_install_candidates() -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+
+    let exe_name = if cfg!(windows) { "pwsh.exe" } else { "pwsh" };
+    if let Some(path) = env::var_os("PATH") {
+        for dir in env::split_paths(&path) {
+            let pwsh_exe = dir.join(exe_name);
+            if pwsh_exe.is_file() {
+                candidates.extend(resolve_pwsh_dir(&pwsh_exe));
+            }
+        }
+    }
+
+    if cfg!(windows) {
+        for var in ["ProgramFiles", "ProgramFiles(x86)", "ProgramW6432"] {
+            if let Some(program_files) = env::var_os(var) {
+                candidates.extend(subdirs(&PathBuf::from(program_files).join("PowerShell")));
+            }
+        }
+    } else {
+        candidates.extend(subdirs(Path::new("/opt/microsoft/powershell")));
+        candidates.extend(subdirs(Path::new("/usr/local/microsoft/powershell")));
+        if cfg!(target_os = "macos") {
+            candidates.extend(subdirs(Path::new("/opt/homebrew/microsoft/powershell")));
         } else {
-            return Some(pwsh_exe);
+            candidates.push(PathBuf::from("/snap/powershell/current/opt/powershell"));
+            candidates.push(PathBuf::from(
+                "/snap/powershell-preview/current/opt/powershell",
+            ));
         }
     }
-    None
+
+    // dotnet global tools installed in the default tool path
+    let home = env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" });
+    if let Some(home) = home {
+        candidates.extend(tool_store_dirs(
+            &PathBuf::from(home).join(".dotnet").join("tools"),
+        ));
+    }
+
+    candidates
+}
+
+// A dotnet global tool is a shim apphost in the tool path, the package itself is
+// extracted to <tool path>/.store/<package>/<version>/<package>/<version>/tools/<tfm>/any
+fn tool_store_dirs(tool_path: &Path) -> Vec<PathBuf> {
+    let store = tool_path.join(".store");
+    let mut dirs = Vec::new();
+    for package in ["powershell", "powershell.preview"] {
+        for version in subdirs(&store.join(package)) {
+            let version_name = match version.file_name() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            let tools = version.join(package).join(version_name).join("tools");
+            for tfm in subdirs(&tools) {
+                dirs.push(tfm.join("any"));
+            }
+        }
+    }
+    dirs
+}
+
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+                .collect()
+        })
+        .unwrap_or_default();
+    dirs.sort();
+    dirs
 }
 
+/// Returns the path of the `pwsh` executable found in `PATH`, with symbolic links resolved.
+pub fn find_pwsh_exe() -> Option<PathBuf> {
+    let pwsh_exe = which::which("pwsh").ok()?;
+    Some(canonicalize(&pwsh_exe).unwrap_or(pwsh_exe))
+}
+
+/// Returns the installation directory of the `pwsh` executable found in `PATH`.
 pub fn find_pwsh_dir() -> Option<PathBuf> {
-    if let Some(mut pwsh_exe) = find_pwsh_exe() {
-        pwsh_exe.pop();
-        return Some(pwsh_exe);
+    resolve_pwsh_dir(&which::which("pwsh").ok()?)
+}
+
+/// Resolves the installation directory of a `pwsh` executable.
+///
+/// Symbolic links are followed, including relative and chained links such as
+/// the ones created by update-alternatives. If the executable is a dotnet global
+/// tool shim, the highest stable version in the tool store is returned, or the
+/// highest preview version if there is no stable one.
+pub fn resolve_pwsh_dir(pwsh_exe: &Path) -> Option<PathBuf> {
+    let pwsh_exe = canonicalize(pwsh_exe)?;
+    let pwsh_dir = pwsh_exe.parent()?;
+    if is_pwsh_dir(pwsh_dir) {
+        return Some(pwsh_dir.to_path_buf());
+    }
+    tool_store_dirs(pwsh_dir)
+        .into_iter()
+        .filter_map(|dir| PwshInstall::from_dir(&dir))
+        .max_by_key(|install| (!install.preview, install.version))
+        .map(|install| install.path)
+}
+
+// fs::canonicalize, without the \\?\ prefix on Windows for paths that do not need it
+fn canonicalize(path: &Path) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).ok()?;
+    if cfg!(windows) {
+        let path_str = path.to_str()?;
+        if let Some(stripped) = path_str.strip_prefix("\\\\?\\") {
+            if stripped.as_bytes().get(1) == Some(&b':') {
+                return Some(PathBuf::from(stripped));
+            }
+        }
     }
-    None
+    Some(path)
 }
 
 #[allow(dead_code)]
@@ -112,11 +112,7 @@ impl KnownHostExitCode {
 
     #[inline]
     pub fn into_result(self) -> Result<(), Error> {
-        if self.is_success() {
-            Ok(())
-        } else {
-            Err(Error::Hostfxr(HostExitCode::Known(self)))
-        }
+        HostExitCode::Known(self).into_result()
     }
 }
 
@@ -168,9 +164,16 @@ impl HostExitCode {
 
     #[inline]
     pub fn into_result(self) -> Result<(), Error> {
-        match self {
-            HostExitCode::Known(code) => code.into_result(),
-            HostExitCode::Unknown(_) => Err(Error::Hostfxr(self)),
+        self.into_result_with_message(String::new())
+    }
+
+    /// Like `into_result`, attaching the message hostfxr reported for the error.
+    #[inline]
+    pub fn into_result_with_message(self, message: String) -> Result<(), Error> {
+        if self.is_success() {
+            Ok(())
+        } else {
+            Err(Error::Hostfxr(self, message))
         }
     }
 }
@@ -1,9 +1,15 @@
-use crate::context::{HostfxrContext, HostfxrHandle, InitializedForCommandLine};
+use crate::context::{
+    HostfxrContext, HostfxrHandle, InitializedForCommandLine, InitializedForRuntimeConfig,
+};
+use crate::error::Error;
 use crate::host_detect::pwsh_host_detect;
 use crate::pdcstring::{PdCStr, PdCString};
 use dlopen::wrapper::{Container, WrapperApi};
 use std::borrow::BorrowMut;
+use std::cell::RefCell;
 use std::ffi::OsStr;
+use std::mem;
+use std::path::{Path, PathBuf};
 
 #[cfg(windows)]
 #[allow(non_camel_case_types)]
@@ -28,11 +34,12 @@ pub enum HostfxrDelegateType {
     GetFunctionPointer,
 }
 
+/// The `hostfxr_initialize_parameters` struct, `host_path` and `dotnet_root` may be null.
 #[repr(C)]
 pub struct HostfxrInitializeParameters {
     pub size: usize,
-    pub host_path: Box<PdCStr>,   //*const char_t,
-    pub dotnet_root: Box<PdCStr>, //*const char_t,
+    pub host_path: *const char_t,
+    pub dotnet_root: *const char_t,
 }
 
 pub type Hostfxrhandle = *mut libc::c_void;
@@ -73,12 +80,42 @@ pub struct HostfxrLib {
         delegate: *mut libc::c_void,
     ) -> i32,
     hostfxr_close: unsafe extern "C" fn(host_context_handle: Hostfxrhandle) -> i32,
+    hostfxr_set_error_writer: unsafe extern "C" fn(
+        error_writer: Option<HostfxrErrorWriterFn>,
+    ) -> Option<HostfxrErrorWriterFn>,
+}
+
+pub type HostfxrErrorWriterFn = unsafe extern "C" fn(message: *const char_t);
+
+thread_local! {
+    // Messages written by hostfxr on this thread while capturing, see capture_host_errors
+    static HOST_ERRORS: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub(crate) unsafe extern "C" fn host_error_writer(message: *const char_t) {
+    let message = PdCStr::from_str_ptr(message).to_string_lossy();
+    HOST_ERRORS.with(|errors| {
+        if let Some(errors) = errors.borrow_mut().as_mut() {
+            if !errors.is_empty() {
+                errors.push('\n');
+            }
+            errors.push_str(message.trim_end());
+        }
+    });
+}
+
+// Runs f, returning the messages passed to host_error_writer on this thread meanwhile
+pub(crate) fn capture_host_errors<T>(f: impl FnOnce() -> T) -> (T, String) {
+    let outer = HOST_ERRORS.with(|errors| errors.replace(Some(String::new())));
+    let result = f();
+    let captured = HOST_ERRORS.with(|errors| errors.replace(outer));
+    (result, captured.unwrap_or_default())
 }
 
 impl HostfxrLib {
     #[allow(dead_code)]
-    fn load_lib(path: impl AsRef<OsStr>) -> Result<Container<Self>, Box<dyn std::error::Error>> {
-        Ok(unsafe { Container::load(path)? })
+    fn load_lib(path: impl AsRef<OsStr>) -> Result<Container<Self>, dlopen::Error> {
+        unsafe { Container::load(path) }
     }
 }
 
@@ -101,38 +138,54 @@ pub type GetFunctionPointerFn = unsafe extern "system" fn(
 ) -> i32;
 
 pub struct Hostfxr {
-    pub lib: Container<HostfxrLib>,
+    pub(crate) lib: Container<HostfxrLib>,
 }
 
 impl Hostfxr {
     #[allow(dead_code)]
-    pub fn load_from_path(path: impl AsRef<OsStr>) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(Self {
-            lib: HostfxrLib::load_lib(path)?,
-        })
+    pub fn load_from_path(path: impl AsRef<OsStr>) -> Result<Self, Error> {
+        let lib = HostfxrLib::load_lib(path.as_ref())
+            .map_err(|err| Error::HostfxrLoad(PathBuf::from(path.as_ref()), err))?;
+        Ok(Self { lib })
     }
 
     #[allow(dead_code)]
     pub fn initialize_for_dotnet_command_line(
         &self,
         pwsh_path: impl AsRef<OsStr>,
-    ) -> Result<HostfxrContext<InitializedForCommandLine>, Box<dyn std::error::Error>> {
+    ) -> Result<HostfxrContext<'_, InitializedForCommandLine>, Error> {
+        self.initialize_for_dotnet_command_line_with_args(pwsh_path, &[] as &[&OsStr])
+    }
+
+    /// Initializes a context for the command line `<app_path> <args>...`, the arguments
+    /// are passed to the application when it is run with `HostfxrContext::run_app`.
+    pub fn initialize_for_dotnet_command_line_with_args(
+        &self,
+        app_path: impl AsRef<OsStr>,
+        args: &[impl AsRef<OsStr>],
+    ) -> Result<HostfxrContext<'_, InitializedForCommandLine>, Error> {
         use crate::host_exit_code::HostExitCode;
         use std::ptr;
 
-        let args = &[PdCString::from_os_str(pwsh_path)?];
+        let mut command_line = vec![PdCString::from_os_str(app_path.as_ref())?];
+        for arg in args {
+            command_line.push(PdCString::from_os_str(arg.as_ref())?);
+        }
+        let argv: Vec<*const char_t> = command_line.iter().map(|arg| arg.as_ptr()).collect();
         let mut host_context_handle = ptr::null::<Hostfxrhandle>() as Hostfxrhandle;
 
-        let result = unsafe {
+        let (result, message) = self.capture_errors(|| unsafe {
             self.lib.hostfxr_initialize_for_dotnet_command_line(
-                args.len() as i32,
-                args.as_ptr() as *const *const char_t,
+                argv.len() as i32,
+                argv.as_ptr(),
                 ptr::null(),
                 host_context_handle.borrow_mut() as *mut _ as Hostfxrhandle, //Initialise nullptr
             )
-        };
+        });
 
-        HostExitCode::from(result).into_result()?;
+        HostExitCode::from(result).into_result().map_err(|_| {
+            Error::ContextInit(PathBuf::from(app_path.as_ref()), result.into(), message)
+        })?;
 
         Ok(HostfxrContext::new(
             unsafe { HostfxrHandle::new_unchecked(host_context_handle) },
@@ -140,79 +193,74 @@ impl Hostfxr {
         ))
     }
 
-    #[allow(dead_code)]
+    /// Initializes a context from a runtimeconfig.json, to load components into the runtime.
+    ///
+    /// `host_path` is the path of the native host and `dotnet_root` the directory
+    /// of the runtime to use, hostfxr picks defaults for the ones left as `None`.
     pub fn initialize_for_runtime_config(
         &self,
-        runtime_config_path: impl AsRef<PdCStr>,
-        parameters: Box<HostfxrInitializeParameters>, //*const HostfxrInitializeParameters,
-        host_context_handle: *mut Hostfxrhandle,
-    ) -> i32 {
-        unsafe {
+        runtime_config_path: impl AsRef<OsStr>,
+        host_path: Option<&Path>,
+        dotnet_root: Option<&Path>,
+    ) -> Result<HostfxrContext<'_, InitializedForRuntimeConfig>, Error> {
+        use crate::host_exit_code::HostExitCode;
+        use std::ptr;
+
+        let config_path = PdCString::from_os_str(runtime_config_path.as_ref())?;
+        let host_path = host_path
+            .map(|path| PdCString::from_os_str(path.as_os_str()))
+            .transpose()?;
+        let dotnet_root = dotnet_root
+            .map(|path| PdCString::from_os_str(path.as_os_str()))
+            .transpose()?;
+        let parameters = HostfxrInitializeParameters {
+            size: mem::size_of::<HostfxrInitializeParameters>(),
+            host_path: host_path.as_ref().map_or(ptr::null(), |path| path.as_ptr()),
+            dotnet_root: dotnet_root
+                .as_ref()
+                .map_or(ptr::null(), |path| path.as_ptr()),
+        };
+        let mut host_context_handle: Hostfxrhandle = ptr::null_mut();
+
+        let (result, message) = self.capture_errors(|| unsafe {
             self.lib.hostfxr_initialize_for_runtime_config(
-                runtime_config_path.as_ref().as_ptr(),
-                parameters.as_ref(),
-                host_context_handle,
+                config_path.as_ptr(),
+                &parameters,
+                &mut host_context_handle,
             )
-        }
-    }
+        });
 
-    #[allow(dead_code)]
-    pub fn get_runtime_property_value(
-        &self,
-        host_context_handle: Hostfxrhandle,
-        name: impl AsRef<PdCStr>,  //*const char_t,
-        value: impl AsRef<PdCStr>, //*mut *const char_t,
-    ) -> i32 {
-        unsafe {
-            self.lib.hostfxr_get_runtime_property_value(
-                host_context_handle,
-                name.as_ref().as_ptr(),
-                value.as_ref().as_ptr().borrow_mut(),
+        HostExitCode::from(result).into_result().map_err(|_| {
+            Error::ContextInit(
+                PathBuf::from(runtime_config_path.as_ref()),
+                result.into(),
+                message,
             )
-        }
-    }
+        })?;
 
-    #[allow(dead_code)]
-    pub fn set_runtime_property_value(
-        &self,
-        host_context_handle: Hostfxrhandle,
-        name: impl AsRef<PdCStr>,  //*const char_t,
-        value: impl AsRef<PdCStr>, //*const char_t,
-    ) -> i32 {
-        unsafe {
-            self.lib.hostfxr_set_runtime_property_value(
-                host_context_handle,
-                name.as_ref().as_ptr(),
-                value.as_ref().as_ptr(),
-            )
-        }
+        Ok(HostfxrContext::new(
+            unsafe { HostfxrHandle::new_unchecked(host_context_handle) },
+            self,
+        ))
     }
 
-    #[allow(dead_code)]
-    pub fn get_runtime_properties(
-        &self,
-        host_context_handle: Hostfxrhandle,
-        count: &mut usize,          //*mut libc::size_t,
-        keys: impl AsRef<PdCStr>,   //*mut *const char_t,
-        values: impl AsRef<PdCStr>, //*mut *const char_t,
-    ) -> i32 {
-        unsafe {
-            self.lib.hostfxr_get_runtime_properties(
-                host_context_handle,
-                count,
-                keys.as_ref().as_ptr().borrow_mut(),
-                values.as_ref().as_ptr().borrow_mut(),
-            )
-        }
+    /// Runs f with hostfxr error messages on this thread captured instead of written to
+    /// stderr, and returns them along with the result of f.
+    pub(crate) fn capture_errors<T>(&self, f: impl FnOnce() -> T) -> (T, String) {
+        capture_host_errors(|| {
+            let previous = unsafe { self.lib.hostfxr_set_error_writer(Some(host_error_writer)) };
+            let result = f();
+            unsafe { self.lib.hostfxr_set_error_writer(previous) };
+            result
+        })
     }
 
-    #[allow(dead_code)]
-    pub fn run_app(&self, host_context_handle: Hostfxrhandle) -> i32 {
+    pub(crate) fn run_app(&self, host_context_handle: Hostfxrhandle) -> i32 {
         unsafe { self.lib.hostfxr_run_app(host_context_handle) }
     }
 
     #[allow(dead_code)]
-    pub fn get_runtime_delegate(
+    pub(crate) fn get_runtime_delegate(
         &self,
         host_context_handle: Hostfxrhandle,
         delegate_type: HostfxrDelegateType,
@@ -224,16 +272,20 @@ impl Hostfxr {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn close(&self, host_context_handle: Hostfxrhandle) -> i32 {
+    pub(crate) fn close(&self, host_context_handle: Hostfxrhandle) -> i32 {
         unsafe { self.lib.hostfxr_close(host_context_handle) }
     }
 }
 
 #[allow(dead_code)]
-pub fn load_hostfxr() -> Result<Hostfxr, Box<dyn std::error::Error>> {
+pub fn load_hostfxr() -> Result<Hostfxr, Error> {
     let pwsh_path = pwsh_host_detect()?;
-    Hostfxr::load_from_path(pwsh_path.join(if cfg!(target_os = "windows") {
+    load_hostfxr_from_dir(&pwsh_path)
+}
+
+/// Loads the hostfxr library shipped in a PowerShell installation directory.
+pub fn load_hostfxr_from_dir(pwsh_dir: &Path) -> Result<Hostfxr, Error> {
+    Hostfxr::load_from_path(pwsh_dir.join(if cfg!(target_os = "windows") {
         "hostfxr.dll"
     } else if cfg!(target_os = "linux") {
         "libhostfxr.so"
@@ -1,12 +1,21 @@
+//! Rust PowerShell Hosting Library
+//!
+//! Hosts the PowerShell SDK in-process through hostfxr and exposes a
+//! `PowerShell` type mirroring `System.Management.Automation.PowerShell`,
+//! along with a CLIXML parser for the objects it produces.
+
 mod bindings;
 mod cli_xml;
 mod context;
 mod delegate_loader;
 mod error;
+mod future;
 mod host_detect;
 mod host_exit_code;
 mod hostfxr;
 mod loader;
+mod records;
+mod runtime;
 mod tests;
 mod time;
 
@@ -19,4 +28,38 @@ extern crate quick_error;
 
 /// Module for a platform dependent c-like string type.
 #[macro_use]
-mod pdcstring;
+#[doc(hidden)]
+pub mod pdcstring;
+
+pub use crate::bindings::{PowerShell, PowerShellStream, StopHandle};
+pub use crate::cli_xml::{
+    find_cli_object, parse_cli_xml, CliBool, CliBuffer, CliChar, CliDateTime, CliDecimal,
+    CliDictionary, CliDouble, CliDuration, CliEnumerable, CliFloat, CliGuid, CliInt16, CliInt32,
+    CliInt64, CliInt8, CliList, CliNull, CliObject, CliPathError, CliQueue, CliRef, CliScriptBlock,
+    CliStack, CliString, CliTypeName, CliUInt16, CliUInt32, CliUInt64, CliUInt8, CliUri, CliValue,
+    CliVersion, CliXmlDocument,
+};
+pub use crate::context::{HostfxrContext, InitializedForCommandLine, InitializedForRuntimeConfig};
+pub use crate::error::Error;
+pub use crate::future::InvokeFuture;
+pub use crate::host_detect::{
+    find_pwsh_dir, find_pwsh_exe, find_pwsh_installs, is_pwsh_dir, pwsh_host_detect,
+    read_pwsh_version, resolve_pwsh_dir, EnvError, PwshArch, PwshInstall, PwshVersion,
+};
+pub use crate::host_exit_code::{HostExitCode, KnownHostExitCode};
+pub use crate::hostfxr::{load_hostfxr, load_hostfxr_from_dir, Hostfxr, HostfxrDelegateType};
+pub use crate::records::{
+    CategoryInfo, DebugRecord, ErrorRecord, InformationRecord, InformationalRecord, ProgressRecord,
+    VerboseRecord, WarningRecord,
+};
+pub use crate::runtime::{
+    PwshHostingMode, PwshRuntime, PwshRuntimeBuilder, PwshRuntimeContext, PwshSource,
+};
+pub use crate::time::DateTime;
+
+/// Commonly used types, meant to be glob imported.
+pub mod prelude {
+    pub use crate::bindings::PowerShell;
+    pub use crate::cli_xml::{parse_cli_xml, CliObject, CliValue};
+    pub use crate::error::Error;
+}
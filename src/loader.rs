@@ -1,42 +1,26 @@
+use crate::context::HostfxrContext;
 use crate::delegate_loader::AssemblyDelegateLoader;
-use crate::host_detect::pwsh_host_detect;
+use crate::error::Error;
 use crate::host_exit_code::HostExitCode;
-use crate::hostfxr::load_hostfxr;
 use crate::pdcstr;
 use crate::pdcstring::PdCString;
+use std::path::Path;
 
 pub const BINDINGS_DLL: &[u8] = include_bytes!("../dotnet/bin/Release/net6.0/Bindings.dll");
 
-pub fn get_assembly_delegate_loader() -> AssemblyDelegateLoader<PdCString> {
-    let pwsh_path = pwsh_host_detect();
-    assert!(pwsh_path.is_ok());
-    let pwsh_path = pwsh_path.unwrap();
+pub fn get_assembly_delegate_loader<I>(
+    ctx: &HostfxrContext<I>,
+    pwsh_path: &Path,
+) -> Result<AssemblyDelegateLoader<PdCString>, Error> {
+    let sma_path = pwsh_path.join("System.Management.Automation.dll");
+    let assembly_path = PdCString::from_os_str(sma_path.as_os_str())?;
 
-    let hostfxr = load_hostfxr();
-    assert!(hostfxr.is_ok());
-    let hostfxr = hostfxr.unwrap();
-
-    let ctx = hostfxr.initialize_for_dotnet_command_line(pwsh_path.join("pwsh.dll"));
-    assert!(ctx.is_ok());
-    let ctx = ctx.unwrap();
-
-    let assembly_path = PdCString::from_os_str(
-        pwsh_path
-            .join("System.Management.Automation.dll")
-            .into_os_string(),
-    );
-    assert!(assembly_path.is_ok());
-    let assembly_path = assembly_path.unwrap();
-
-    let fn_loader = ctx.get_delegate_loader_for_assembly(assembly_path);
-    assert!(fn_loader.is_ok());
-    let fn_loader = fn_loader.unwrap();
+    let fn_loader = ctx.get_delegate_loader_for_assembly(assembly_path)?;
 
     let load_assembly_from_native_memory = fn_loader.get_function_pointer_for_unmanaged_callers_only_method(
         pdcstr!("System.Management.Automation.PowerShellUnsafeAssemblyLoad, System.Management.Automation"),
-    pdcstr!("LoadAssemblyFromNativeMemory"));
-    assert!(load_assembly_from_native_memory.is_ok());
-    let load_assembly_from_native_memory = load_assembly_from_native_memory.unwrap();
+    pdcstr!("LoadAssemblyFromNativeMemory"))
+        .map_err(|_| Error::MissingUnsafeAssemblyLoad(sma_path.clone()))?;
 
     let load_assembly_from_native_memory: extern "system" fn(
         bytes: *const libc::c_uchar,
@@ -44,7 +28,9 @@ pub fn get_assembly_delegate_loader() -> AssemblyDelegateLoader<PdCString> {
     ) -> i32 = unsafe { std::mem::transmute(load_assembly_from_native_memory) };
     let result =
         (load_assembly_from_native_memory)(BINDINGS_DLL.as_ptr(), BINDINGS_DLL.len() as u32);
-    HostExitCode::from(result).into_result().unwrap();
+    HostExitCode::from(result)
+        .into_result()
+        .map_err(|err| Error::BindingsLoad(sma_path, Box::new(err)))?;
 
-    fn_loader
+    Ok(fn_loader)
 }
@@ -72,6 +72,10 @@ impl PdCStr {
         let inner = CStr::from_bytes_with_nul_unchecked(slice);
         PdCStr::from_inner(inner)
     }
+
+    pub unsafe fn from_str_ptr<'a>(ptr: *const i8) -> &'a Self {
+        PdCStr::from_inner(CStr::from_ptr(ptr))
+    }
     pub fn to_os_string(&self) -> OsString {
         self.to_os_str().to_owned()
     }
@@ -95,6 +99,9 @@ impl PdCStr {
     pub fn len(&self) -> usize {
         self.0.to_bytes().len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     pub fn to_string(&self) -> Result<String, str::Utf8Error> {
         self.0.to_str().map(|s| s.to_string())
     }
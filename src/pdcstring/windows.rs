@@ -76,6 +76,10 @@ impl PdCStr {
         PdCStr::from_inner(inner)
     }
 
+    pub unsafe fn from_str_ptr<'a>(ptr: *const u16) -> &'a Self {
+        PdCStr::from_inner(U16CStr::from_ptr_str(ptr))
+    }
+
     pub fn to_os_string(&self) -> OsString {
         self.0.to_os_string()
     }
@@ -97,6 +101,9 @@ impl PdCStr {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     pub fn to_string(&self) -> Result<String, string::FromUtf16Error> {
         self.0.to_string()
     }
@@ -0,0 +1,247 @@
+use crate::cli_xml::{parse_cli_xml, CliObject, CliValue};
+use crate::error::Error;
+use crate::time::DateTime;
+use std::fmt;
+
+// Finds a named property value in a list of deserialized objects, looking at the
+// properties of each object before descending into nested objects such as the
+// Exception property of an ErrorRecord.
+fn find_value<'a>(objs: &'a [CliObject], name: &str) -> Option<&'a CliValue> {
+    for obj in objs {
+        if let Some(value) = obj.get(name) {
+            return Some(value);
+        }
+        for value in obj.properties() {
+            if let Some(nested) = value.as_object() {
+                if let Some(value) = find_value(std::slice::from_ref(nested), name) {
+                    return Some(value);
+                }
+            }
+        }
+    }
+    None
+}
+
+fn find_string(objs: &[CliObject], name: &str) -> Option<String> {
+    find_value(objs, name)
+        .and_then(|value| value.as_str())
+        .map(|value| value.to_string())
+}
+
+fn find_i32(objs: &[CliObject], name: &str) -> Option<i32> {
+    find_value(objs, name).and_then(|value| value.as_i32())
+}
+
+fn find_object<'a>(objs: &'a [CliObject], name: &str) -> Option<&'a CliObject> {
+    find_value(objs, name).and_then(|value| value.as_object())
+}
+
+// ErrorCategoryInfo Class
+// https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.errorcategoryinfo
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CategoryInfo {
+    pub category: i32,
+    pub activity: Option<String>,
+    pub reason: Option<String>,
+    pub target_name: Option<String>,
+    pub target_type: Option<String>,
+    pub message: Option<String>,
+}
+
+// ErrorRecord Class
+// https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.errorrecord
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ErrorRecord {
+    pub exception_type: Option<String>,
+    pub message: String,
+    pub category_info: CategoryInfo,
+    pub fully_qualified_error_id: Option<String>,
+    pub script_stack_trace: Option<String>,
+}
+
+impl ErrorRecord {
+    pub fn from_cli_xml(cli_xml: &str) -> Result<ErrorRecord, Error> {
+        Ok(Self::from_cli_objects(&parse_cli_xml(cli_xml)?))
+    }
+
+    pub fn from_cli_objects(objs: &[CliObject]) -> ErrorRecord {
+        let category_info = CategoryInfo {
+            category: find_i32(objs, "ErrorCategory_Category").unwrap_or_default(),
+            activity: find_string(objs, "ErrorCategory_Activity"),
+            reason: find_string(objs, "ErrorCategory_Reason"),
+            target_name: find_string(objs, "ErrorCategory_TargetName"),
+            target_type: find_string(objs, "ErrorCategory_TargetType"),
+            message: find_string(objs, "ErrorCategory_Message"),
+        };
+
+        // the exception type names are only known when the exception was
+        // serialized as a nested object, otherwise fall back to the reason
+        let exception_type = find_object(objs, "Exception")
+            .and_then(|exception| exception.type_names.first().cloned())
+            .or_else(|| category_info.reason.clone());
+
+        let message = find_string(objs, "ErrorDetails_Message")
+            .or_else(|| find_string(objs, "Message"))
+            .or_else(|| objs.first().and_then(|obj| obj.string_repr.clone()))
+            .unwrap_or_default();
+
+        ErrorRecord {
+            exception_type,
+            message,
+            category_info,
+            fully_qualified_error_id: find_string(objs, "FullyQualifiedErrorId"),
+            script_stack_trace: find_string(objs, "ErrorDetails_ScriptStackTrace"),
+        }
+    }
+}
+
+impl fmt::Display for ErrorRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(category_message) = &self.category_info.message {
+            write!(f, " ({})", category_message)?;
+        }
+        if let Some(error_id) = &self.fully_qualified_error_id {
+            write!(f, " [{}]", error_id)?;
+        }
+        Ok(())
+    }
+}
+
+// InformationalRecord Class
+// https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.informationalrecord
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct InformationalRecord {
+    pub message: String,
+}
+
+pub type WarningRecord = InformationalRecord;
+pub type VerboseRecord = InformationalRecord;
+pub type DebugRecord = InformationalRecord;
+
+impl InformationalRecord {
+    pub fn from_cli_xml(cli_xml: &str) -> Result<InformationalRecord, Error> {
+        Ok(Self::from_cli_objects(&parse_cli_xml(cli_xml)?))
+    }
+
+    pub fn from_cli_objects(objs: &[CliObject]) -> InformationalRecord {
+        let message = find_string(objs, "InformationalRecord_Message")
+            .or_else(|| find_string(objs, "Message"))
+            .or_else(|| objs.first().and_then(|obj| obj.string_repr.clone()))
+            .unwrap_or_default();
+
+        InformationalRecord { message }
+    }
+}
+
+impl fmt::Display for InformationalRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+// InformationRecord Class
+// https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.informationrecord
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct InformationRecord {
+    pub message_data: String,
+    pub source: Option<String>,
+    pub time_generated: Option<DateTime>,
+    pub tags: Vec<String>,
+    pub user: Option<String>,
+    pub computer: Option<String>,
+    pub process_id: Option<u32>,
+}
+
+impl InformationRecord {
+    pub fn from_cli_xml(cli_xml: &str) -> Result<InformationRecord, Error> {
+        Ok(Self::from_cli_objects(&parse_cli_xml(cli_xml)?))
+    }
+
+    pub fn from_cli_objects(objs: &[CliObject]) -> InformationRecord {
+        // Write-Host wraps its message in a HostInformationMessage object
+        let message_data = find_string(objs, "MessageData")
+            .or_else(|| find_string(objs, "Message"))
+            .or_else(|| objs.first().and_then(|obj| obj.string_repr.clone()))
+            .unwrap_or_default();
+
+        let tags = find_object(objs, "Tags")
+            .map(|tags| {
+                tags.value
+                    .as_deref()
+                    .and_then(|value| value.as_collection())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|tag| tag.as_str())
+                    .map(|tag| tag.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        InformationRecord {
+            message_data,
+            source: find_string(objs, "Source"),
+            time_generated: find_value(objs, "TimeGenerated")
+                .and_then(|value| value.as_datetime())
+                .cloned(),
+            tags,
+            user: find_string(objs, "User"),
+            computer: find_string(objs, "Computer"),
+            process_id: find_value(objs, "ProcessId").and_then(|value| value.as_u32()),
+        }
+    }
+}
+
+impl fmt::Display for InformationRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message_data)
+    }
+}
+
+// ProgressRecord Class
+// https://learn.microsoft.com/en-us/dotnet/api/system.management.automation.progressrecord
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ProgressRecord {
+    pub activity_id: i32,
+    pub parent_activity_id: i32,
+    pub activity: String,
+    pub status_description: String,
+    pub current_operation: Option<String>,
+    pub percent_complete: i32,
+    pub seconds_remaining: i32,
+    pub record_type: Option<String>,
+}
+
+impl ProgressRecord {
+    pub fn from_cli_xml(cli_xml: &str) -> Result<ProgressRecord, Error> {
+        Ok(Self::from_cli_objects(&parse_cli_xml(cli_xml)?))
+    }
+
+    pub fn from_cli_objects(objs: &[CliObject]) -> ProgressRecord {
+        ProgressRecord {
+            activity_id: find_i32(objs, "ActivityId").unwrap_or_default(),
+            parent_activity_id: find_i32(objs, "ParentActivityId").unwrap_or(-1),
+            activity: find_string(objs, "Activity").unwrap_or_default(),
+            status_description: find_string(objs, "StatusDescription").unwrap_or_default(),
+            current_operation: find_string(objs, "CurrentOperation"),
+            percent_complete: find_i32(objs, "PercentComplete").unwrap_or(-1),
+            seconds_remaining: find_i32(objs, "SecondsRemaining").unwrap_or(-1),
+            record_type: find_object(objs, "RecordType").and_then(|obj| obj.string_repr.clone()),
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.record_type.as_deref() == Some("Completed")
+    }
+}
+
+impl fmt::Display for ProgressRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.activity, self.status_description)
+    }
+}
@@ -0,0 +1,427 @@
+use crate::bindings::Bindings;
+use crate::context::{HostfxrContext, InitializedForCommandLine, InitializedForRuntimeConfig};
+use crate::error::Error;
+use crate::host_detect::{find_pwsh_dir, is_pwsh_dir, read_pwsh_version, EnvError, PwshVersion};
+use crate::host_exit_code::HostExitCode;
+use crate::hostfxr::{load_hostfxr_from_dir, Hostfxr};
+use crate::loader::get_assembly_delegate_loader;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+static RUNTIME: Mutex<Option<&'static PwshRuntime>> = Mutex::new(None);
+
+// The installation `PwshRuntimeBuilder::run_app` started pwsh from, which then owns
+// the runtime of the process. Only locked while holding the RUNTIME lock.
+static APP_PWSH_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+// The installation and error of an initialization that failed once hostfxr was
+// initialized, which cannot be retried. Only locked while holding the RUNTIME lock.
+static RUNTIME_ERROR: Mutex<Option<(PathBuf, String)>> = Mutex::new(None);
+
+// hostfxr refuses to be initialized a second time, so a failure once it was
+// initialized is kept and returned by later attempts instead of retrying.
+fn keep_error(pwsh_dir: PathBuf, err: Error) -> Error {
+    *RUNTIME_ERROR
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some((pwsh_dir, err.to_string()));
+    err
+}
+
+// hostfxr libraries by PowerShell installation directory, loaded at most once each
+static HOSTFXR: Mutex<Vec<(PathBuf, &'static Hostfxr)>> = Mutex::new(Vec::new());
+
+// The runtime cannot be unloaded, so neither can hostfxr. It is kept loaded even if
+// initialization fails, and reused by later attempts instead of being loaded again.
+fn load_hostfxr_once(pwsh_dir: &Path) -> Result<&'static Hostfxr, Error> {
+    let mut loaded = HOSTFXR
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((_, hostfxr)) = loaded.iter().find(|(dir, _)| same_dir(dir, pwsh_dir)) {
+        return Ok(*hostfxr);
+    }
+    let hostfxr: &'static Hostfxr = Box::leak(Box::new(load_hostfxr_from_dir(pwsh_dir)?));
+    loaded.push((pwsh_dir.to_path_buf(), hostfxr));
+    Ok(hostfxr)
+}
+
+/// The PowerShell runtime hosted in the current process.
+///
+/// hostfxr only supports a single runtime per process, so it is initialized
+/// once, on first use, and shared by every `PowerShell` instance afterwards.
+pub struct PwshRuntime {
+    pwsh_dir: PathBuf,
+    context: PwshRuntimeContext,
+    bindings: Bindings,
+}
+
+/// The hostfxr context the PowerShell runtime was initialized with.
+pub enum PwshRuntimeContext {
+    CommandLine(HostfxrContext<'static, InitializedForCommandLine>),
+    RuntimeConfig(HostfxrContext<'static, InitializedForRuntimeConfig>),
+}
+
+impl PwshRuntimeContext {
+    /// Gets the value of a property of the running runtime, or `None` if it is not set.
+    pub fn get_runtime_property_value(&self, name: &str) -> Result<Option<String>, Error> {
+        match self {
+            PwshRuntimeContext::CommandLine(context) => context.get_runtime_property_value(name),
+            PwshRuntimeContext::RuntimeConfig(context) => context.get_runtime_property_value(name),
+        }
+    }
+
+    /// Gets all the properties of the running runtime and their values.
+    pub fn get_runtime_properties(&self) -> Result<HashMap<String, String>, Error> {
+        match self {
+            PwshRuntimeContext::CommandLine(context) => context.get_runtime_properties(),
+            PwshRuntimeContext::RuntimeConfig(context) => context.get_runtime_properties(),
+        }
+    }
+}
+
+/// How hostfxr is initialized to host PowerShell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PwshHostingMode {
+    /// Initialize for the `pwsh.dll` command line, like the `pwsh` executable does.
+    CommandLine,
+    /// Initialize from `pwsh.runtimeconfig.json`, loading PowerShell as a component
+    /// without the application semantics of the command line context.
+    RuntimeConfig,
+}
+
+// The host context is only used to resolve function pointers, which is safe from any thread.
+unsafe impl Send for PwshRuntime {}
+unsafe impl Sync for PwshRuntime {}
+
+impl PwshRuntime {
+    /// Returns the process-wide runtime, initializing it if needed.
+    ///
+    /// The runtime is initialized with the default `PwshRuntimeBuilder` settings,
+    /// use `PwshRuntimeBuilder::build` beforehand to pin a specific installation.
+    /// If initialization fails before hostfxr is initialized, such as when no
+    /// installation is found, the error is returned and the next call tries again.
+    /// Once hostfxr is initialized it cannot be initialized again in this process,
+    /// so later failures are returned by every call as `Error::RuntimeFailed`.
+    pub fn get() -> Result<&'static PwshRuntime, Error> {
+        Self::get_or_initialize(None)
+    }
+
+    /// Returns a builder to select the PowerShell installation to load.
+    pub fn builder() -> PwshRuntimeBuilder {
+        PwshRuntimeBuilder::new()
+    }
+
+    fn get_or_initialize(
+        builder: Option<&PwshRuntimeBuilder>,
+    ) -> Result<&'static PwshRuntime, Error> {
+        let mut runtime = RUNTIME
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(pwsh_dir) = APP_PWSH_DIR
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+        {
+            return Err(Error::RuntimeInitialized(pwsh_dir));
+        }
+        if let Some((pwsh_dir, message)) = RUNTIME_ERROR
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+        {
+            return Err(Error::RuntimeFailed(pwsh_dir, message));
+        }
+        if let Some(runtime) = *runtime {
+            if let Some(builder) = builder {
+                let pwsh_dir = builder.find_pwsh_dir()?;
+                if !same_dir(&pwsh_dir, &runtime.pwsh_dir) {
+                    return Err(Error::RuntimeInitialized(runtime.pwsh_dir.clone()));
+                }
+            }
+            return Ok(runtime);
+        }
+        let default_builder = PwshRuntimeBuilder::new();
+        let builder = builder.unwrap_or(&default_builder);
+        let pwsh_dir = builder.find_pwsh_dir()?;
+        let initialized: &'static PwshRuntime =
+            Box::leak(Box::new(Self::initialize(pwsh_dir, builder)?));
+        *runtime = Some(initialized);
+        Ok(initialized)
+    }
+
+    fn initialize(pwsh_dir: PathBuf, builder: &PwshRuntimeBuilder) -> Result<PwshRuntime, Error> {
+        let hostfxr = load_hostfxr_once(&pwsh_dir)?;
+
+        // SuccessHostAlreadyInitialized and SuccessDifferentRuntimeProperties are
+        // accepted as success, the context is then attached to the existing runtime
+        let context = match builder.hosting_mode {
+            PwshHostingMode::CommandLine => PwshRuntimeContext::CommandLine(
+                hostfxr.initialize_for_dotnet_command_line(pwsh_dir.join("pwsh.dll"))?,
+            ),
+            PwshHostingMode::RuntimeConfig => {
+                let host_path = pwsh_dir.join(if cfg!(windows) { "pwsh.exe" } else { "pwsh" });
+                PwshRuntimeContext::RuntimeConfig(hostfxr.initialize_for_runtime_config(
+                    pwsh_dir.join("pwsh.runtimeconfig.json"),
+                    Some(&host_path),
+                    Some(&pwsh_dir),
+                )?)
+            }
+        };
+
+        Self::start(pwsh_dir.clone(), builder, context).map_err(|err| keep_error(pwsh_dir, err))
+    }
+
+    fn start(
+        pwsh_dir: PathBuf,
+        builder: &PwshRuntimeBuilder,
+        context: PwshRuntimeContext,
+    ) -> Result<PwshRuntime, Error> {
+        // runtime properties must be set before the delegate loader starts the runtime
+        let fn_loader = match &context {
+            PwshRuntimeContext::CommandLine(context) => {
+                builder.set_runtime_properties(context)?;
+                get_assembly_delegate_loader(context, &pwsh_dir)?
+            }
+            PwshRuntimeContext::RuntimeConfig(context) => {
+                builder.set_runtime_properties(context)?;
+                get_assembly_delegate_loader(context, &pwsh_dir)?
+            }
+        };
+
+        let bindings = Bindings::new_with_loader(&fn_loader).map_err(|err| {
+            Error::BindingsLoad(
+                pwsh_dir.join("System.Management.Automation.dll"),
+                Box::new(err),
+            )
+        })?;
+
+        Ok(PwshRuntime {
+            pwsh_dir,
+            context,
+            bindings,
+        })
+    }
+
+    /// The PowerShell installation directory the runtime was loaded from.
+    pub fn pwsh_dir(&self) -> &Path {
+        &self.pwsh_dir
+    }
+
+    /// The hostfxr context the runtime was initialized with.
+    pub fn context(&self) -> &PwshRuntimeContext {
+        &self.context
+    }
+
+    pub(crate) fn bindings(&self) -> &Bindings {
+        &self.bindings
+    }
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// A place to look for a PowerShell installation directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PwshSource {
+    /// The directory named by the environment variable, `PWSH_HOST_DIR` by default.
+    Environment,
+    /// The directories passed to `PwshRuntimeBuilder::install_dir`, in order.
+    InstallDir,
+    /// The directory of the `pwsh` executable found in `PATH`.
+    Path,
+}
+
+/// Selects the PowerShell installation the process-wide runtime is loaded from.
+///
+/// Candidate directories are taken from each source in the search order, which
+/// defaults to the environment override, then explicit install dirs, then `PATH`.
+/// The first candidate that is a PowerShell installation within the version
+/// bounds is selected.
+#[derive(Debug, Clone)]
+pub struct PwshRuntimeBuilder {
+    install_dirs: Vec<PathBuf>,
+    env_var: String,
+    min_version: Option<PwshVersion>,
+    max_version: Option<PwshVersion>,
+    search_order: Vec<PwshSource>,
+    hosting_mode: PwshHostingMode,
+    runtime_properties: Vec<(String, Option<String>)>,
+}
+
+impl Default for PwshRuntimeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PwshRuntimeBuilder {
+    pub const DEFAULT_ENV_VAR: &'static str = "PWSH_HOST_DIR";
+
+    pub fn new() -> Self {
+        Self {
+            install_dirs: Vec::new(),
+            env_var: Self::DEFAULT_ENV_VAR.to_string(),
+            min_version: None,
+            max_version: None,
+            search_order: vec![
+                PwshSource::Environment,
+                PwshSource::InstallDir,
+                PwshSource::Path,
+            ],
+            hosting_mode: PwshHostingMode::CommandLine,
+            runtime_properties: Vec::new(),
+        }
+    }
+
+    /// Adds an installation directory to consider, can be called more than once.
+    pub fn install_dir(mut self, pwsh_dir: impl Into<PathBuf>) -> Self {
+        self.install_dirs.push(pwsh_dir.into());
+        self
+    }
+
+    /// Sets the name of the environment variable overriding the installation directory.
+    pub fn env_var(mut self, name: impl Into<String>) -> Self {
+        self.env_var = name.into();
+        self
+    }
+
+    /// Sets the lowest PowerShell version accepted, inclusive.
+    pub fn min_version(mut self, version: PwshVersion) -> Self {
+        self.min_version = Some(version);
+        self
+    }
+
+    /// Sets the highest PowerShell version accepted, inclusive.
+    pub fn max_version(mut self, version: PwshVersion) -> Self {
+        self.max_version = Some(version);
+        self
+    }
+
+    /// Sets the sources searched, in order of preference. Sources left out are not searched.
+    pub fn search_order(mut self, sources: &[PwshSource]) -> Self {
+        self.search_order = sources.to_vec();
+        self
+    }
+
+    /// Sets how hostfxr is initialized, `PwshHostingMode::CommandLine` by default.
+    pub fn hosting_mode(mut self, hosting_mode: PwshHostingMode) -> Self {
+        self.hosting_mode = hosting_mode;
+        self
+    }
+
+    /// Sets a runtime property before the runtime starts, such as `System.GC.Server`.
+    ///
+    /// A `None` value removes the property from the ones read from the runtime config.
+    pub fn runtime_property(mut self, name: impl Into<String>, value: Option<&str>) -> Self {
+        self.runtime_properties
+            .push((name.into(), value.map(str::to_string)));
+        self
+    }
+
+    /// Returns the installation directory selected by the current settings.
+    pub fn find_pwsh_dir(&self) -> Result<PathBuf, EnvError> {
+        let mut tried = Vec::new();
+        for source in &self.search_order {
+            for pwsh_dir in self.candidates(*source) {
+                if is_pwsh_dir(&pwsh_dir) && self.version_matches(&pwsh_dir) {
+                    return Ok(pwsh_dir);
+                }
+                tried.push(pwsh_dir);
+            }
+        }
+        if tried.is_empty() {
+            Err(EnvError::Missing)
+        } else {
+            Err(EnvError::NoMatchingInstall(tried))
+        }
+    }
+
+    /// Initializes the process-wide runtime from the selected installation.
+    ///
+    /// If the runtime is already initialized, it is returned when it was loaded
+    /// from the same directory, and `Error::RuntimeInitialized` otherwise.
+    pub fn build(&self) -> Result<&'static PwshRuntime, Error> {
+        PwshRuntime::get_or_initialize(Some(self))
+    }
+
+    /// Runs `pwsh` in-process from the selected installation with the given arguments,
+    /// and returns its exit code once it exits.
+    ///
+    /// Runtime properties are applied before `pwsh` starts. `pwsh` then owns the runtime
+    /// of the process, so this cannot be used once the process-wide runtime is initialized,
+    /// and `Error::RuntimeInitialized` is returned by any later attempt to initialize it,
+    /// including from another thread while `pwsh` is running.
+    pub fn run_app(&self, args: &[impl AsRef<OsStr>]) -> Result<HostExitCode, Error> {
+        let context = {
+            let runtime = RUNTIME
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(runtime) = *runtime {
+                return Err(Error::RuntimeInitialized(runtime.pwsh_dir.clone()));
+            }
+            let mut app_pwsh_dir = APP_PWSH_DIR
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(pwsh_dir) = app_pwsh_dir.as_ref() {
+                return Err(Error::RuntimeInitialized(pwsh_dir.clone()));
+            }
+            if let Some((pwsh_dir, message)) = RUNTIME_ERROR
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone()
+            {
+                return Err(Error::RuntimeFailed(pwsh_dir, message));
+            }
+            let pwsh_dir = self.find_pwsh_dir()?;
+            let hostfxr = load_hostfxr_once(&pwsh_dir)?;
+            let context = hostfxr
+                .initialize_for_dotnet_command_line_with_args(pwsh_dir.join("pwsh.dll"), args)?;
+            if let Err(err) = self.set_runtime_properties(&context) {
+                return Err(keep_error(pwsh_dir, err));
+            }
+            // the runtime is loaded by hostfxr_run_app, which runs without the locks held
+            *app_pwsh_dir = Some(pwsh_dir);
+            context
+        };
+        Ok(context.run_app())
+    }
+
+    fn set_runtime_properties<I>(&self, context: &HostfxrContext<I>) -> Result<(), Error> {
+        for (name, value) in &self.runtime_properties {
+            context.set_runtime_property_value(name, value.as_deref())?;
+        }
+        Ok(())
+    }
+
+    fn candidates(&self, source: PwshSource) -> Vec<PathBuf> {
+        match source {
+            PwshSource::Environment => env::var_os(&self.env_var)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .into_iter()
+                .collect(),
+            PwshSource::InstallDir => self.install_dirs.clone(),
+            PwshSource::Path => find_pwsh_dir().into_iter().collect(),
+        }
+    }
+
+    fn version_matches(&self, pwsh_dir: &Path) -> bool {
+        if self.min_version.is_none() && self.max_version.is_none() {
+            return true;
+        }
+        match read_pwsh_version(pwsh_dir) {
+            Some(version) => {
+                !matches!(self.min_version, Some(min) if version < min)
+                    && !matches!(self.max_version, Some(max) if version > max)
+            }
+            None => false,
+        }
+    }
+}
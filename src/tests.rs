@@ -1,7 +1,33 @@
 #[cfg(test)]
 mod pwsh {
-    use crate::bindings::PowerShell;
-    use crate::cli_xml::{parse_cli_xml, CliObject};
+    use crate::bindings::{stream_callback, stream_callbacks, PowerShell, PowerShellStream};
+    use crate::cli_xml::{
+        find_cli_object, parse_cli_xml, CliDictionary, CliInt32, CliList, CliObject, CliPathError,
+        CliScriptBlock, CliValue,
+    };
+    use crate::context::{CloseHostfxrHandle, HostfxrHandle, OwnedHostfxrHandle};
+    use crate::error::Error;
+    use crate::host_detect::{
+        read_pwsh_installs, read_pwsh_version, resolve_pwsh_dir, EnvError, PwshArch, PwshInstall,
+        PwshVersion,
+    };
+    use crate::host_exit_code::{HostExitCode, KnownHostExitCode};
+    use crate::hostfxr::{
+        capture_host_errors, host_error_writer, Hostfxr, HostfxrErrorWriterFn,
+        HostfxrInitializeParameters, Hostfxrhandle,
+    };
+    use crate::pdcstr;
+    use crate::records::{ErrorRecord, ProgressRecord, WarningRecord};
+    use crate::runtime::{PwshRuntime, PwshRuntimeBuilder, PwshSource};
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::fs;
+    use std::future::Future;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+    use std::time::Duration;
     use uuid::Uuid;
 
     #[test]
@@ -10,18 +36,21 @@ mod pwsh {
 
         // Get-Command -CommandType Cmdlet -Name *-Object -Module Microsoft.PowerShell.Utility |
         // Select-Object -ExpandProperty Name | Set-Variable -Name UtilityCommands
-        pwsh.add_command("Get-Command");
-        pwsh.add_parameter_string("-CommandType", "Cmdlet");
-        pwsh.add_parameter_string("-Name", "*-Object");
-        pwsh.add_parameter_string("-Module", "Microsoft.PowerShell.Utility");
-        pwsh.add_command("Select-Object");
-        pwsh.add_parameter_string("-ExpandProperty", "Name");
-        pwsh.add_command("Set-Variable");
-        pwsh.add_parameter_string("-Name", "UtilityCommands");
-        pwsh.add_statement();
-        pwsh.invoke(true);
-
-        let cmds_txt = pwsh.export_to_string("UtilityCommands");
+        pwsh.add_command("Get-Command").unwrap();
+        pwsh.add_parameter_string("-CommandType", "Cmdlet").unwrap();
+        pwsh.add_parameter_string("-Name", "*-Object").unwrap();
+        pwsh.add_parameter_string("-Module", "Microsoft.PowerShell.Utility")
+            .unwrap();
+        pwsh.add_command("Select-Object").unwrap();
+        pwsh.add_parameter_string("-ExpandProperty", "Name")
+            .unwrap();
+        pwsh.add_command("Set-Variable").unwrap();
+        pwsh.add_parameter_string("-Name", "UtilityCommands")
+            .unwrap();
+        pwsh.add_statement().unwrap();
+        pwsh.invoke(true).unwrap();
+
+        let cmds_txt = pwsh.export_to_string("UtilityCommands").unwrap();
         let pwsh_cmds: Vec<&str> = cmds_txt.lines().collect();
 
         println!("\nCommands (text):");
@@ -35,25 +64,26 @@ mod pwsh {
         assert_eq!(pwsh_cmds.get(2), Some(&"Measure-Object"));
 
         // Get-Date -UnixTimeSeconds 1577836800 | Set-Variable -Name Date
-        pwsh.add_command("Get-Date");
-        pwsh.add_parameter_long("-UnixTimeSeconds", 1577836800);
-        pwsh.add_command("Set-Variable");
-        pwsh.add_parameter_string("-Name", "Date");
-        pwsh.add_statement();
-        pwsh.invoke(true);
-
-        let date_json = pwsh.export_to_json("Date");
+        pwsh.add_command("Get-Date").unwrap();
+        pwsh.add_parameter_long("-UnixTimeSeconds", 1577836800)
+            .unwrap();
+        pwsh.add_command("Set-Variable").unwrap();
+        pwsh.add_parameter_string("-Name", "Date").unwrap();
+        pwsh.add_statement().unwrap();
+        pwsh.invoke(true).unwrap();
+
+        let date_json = pwsh.export_to_json("Date").unwrap();
         println!("\nDate (JSON):\n{}", &date_json);
         assert_eq!(&date_json, "\"2019-12-31T19:00:00-05:00\"");
 
         // Get-Verb -Verb Test | Set-Variable -Name Verb
-        pwsh.add_script("Get-Verb -Verb Test");
-        pwsh.add_command("Set-Variable");
-        pwsh.add_parameter_string("-Name", "Verb");
-        pwsh.add_statement();
-        pwsh.invoke(true);
+        pwsh.add_script("Get-Verb -Verb Test").unwrap();
+        pwsh.add_command("Set-Variable").unwrap();
+        pwsh.add_parameter_string("-Name", "Verb").unwrap();
+        pwsh.add_statement().unwrap();
+        pwsh.invoke(true).unwrap();
 
-        let verb_xml = pwsh.export_to_xml("Verb");
+        let verb_xml = pwsh.export_to_xml("Verb").unwrap();
         println!("\nVerb (XML):\n{}", &verb_xml);
         assert!(verb_xml.starts_with(
             "<Objs Version=\"1.1.0.1\" xmlns=\"http://schemas.microsoft.com/powershell/2004/04\">"
@@ -64,6 +94,1016 @@ mod pwsh {
         assert!(verb_xml
             .find("<ToString>System.Management.Automation.VerbInfo</ToString>")
             .is_some());
+
+        // Get-Command -CommandType Cmdlet -Name *-Object -Module Microsoft.PowerShell.Utility |
+        // Select-Object -ExpandProperty Name
+        pwsh.add_command("Get-Command").unwrap();
+        pwsh.add_parameter_string("-CommandType", "Cmdlet").unwrap();
+        pwsh.add_parameter_string("-Name", "*-Object").unwrap();
+        pwsh.add_parameter_string("-Module", "Microsoft.PowerShell.Utility")
+            .unwrap();
+        pwsh.add_command("Select-Object").unwrap();
+        pwsh.add_parameter_string("-ExpandProperty", "Name")
+            .unwrap();
+        let cmd_objs = pwsh.invoke(true).unwrap();
+
+        assert_eq!(cmd_objs.len(), 7);
+        let cmd_name = cmd_objs.first().and_then(|obj| obj.value.as_deref());
+        assert_eq!(
+            cmd_name.and_then(|val| val.as_str()),
+            Some("Compare-Object")
+        );
+
+        // Get-Verb -Verb Test
+        pwsh.add_command("Get-Verb").unwrap();
+        pwsh.add_parameter_string("-Verb", "Test").unwrap();
+        let verb_objs = pwsh.invoke(true).unwrap();
+
+        assert_eq!(verb_objs.len(), 1);
+        let verb_obj = verb_objs.first().unwrap();
+        assert_eq!(
+            verb_obj.type_names.first().map(|s| s.as_str()),
+            Some("System.Management.Automation.VerbInfo")
+        );
+    }
+
+    #[test]
+    fn shared_pwsh_runtime() {
+        let runtime = PwshRuntime::get().unwrap();
+        assert!(std::ptr::eq(runtime, PwshRuntime::get().unwrap()));
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                thread::spawn(move || {
+                    let pwsh = PowerShell::new().unwrap();
+                    pwsh.add_script(&format!("{} * 2", i)).unwrap();
+                    pwsh.invoke(true).unwrap()[0]
+                        .value
+                        .as_ref()
+                        .unwrap()
+                        .as_i32()
+                })
+            })
+            .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap(), Some(i as i32 * 2));
+        }
+    }
+
+    #[test]
+    fn run_pwsh_app_after_runtime() {
+        let runtime = PwshRuntime::get().unwrap();
+
+        // the hosted runtime cannot be handed over to the pwsh command line
+        let result = PwshRuntimeBuilder::new()
+            .install_dir(runtime.pwsh_dir())
+            .search_order(&[PwshSource::InstallDir])
+            .run_app(&["-NoProfile", "-Command", "exit 3"]);
+        assert!(
+            matches!(result, Err(Error::RuntimeInitialized(ref dir)) if dir == runtime.pwsh_dir())
+        );
+    }
+
+    #[test]
+    fn pwsh_runtime_properties() {
+        let context = PwshRuntime::get().unwrap().context();
+
+        let base_dir = context
+            .get_runtime_property_value("APP_CONTEXT_BASE_DIRECTORY")
+            .unwrap();
+        assert!(base_dir.is_some());
+        assert_eq!(
+            context
+                .get_runtime_property_value("PWSH_HOST_MISSING_PROPERTY")
+                .unwrap(),
+            None
+        );
+
+        let properties = context.get_runtime_properties().unwrap();
+        assert_eq!(
+            properties.get("APP_CONTEXT_BASE_DIRECTORY"),
+            base_dir.as_ref()
+        );
+        assert!(properties.contains_key("TRUSTED_PLATFORM_ASSEMBLIES"));
+    }
+
+    struct RecordingCloser(Mutex<Vec<usize>>);
+
+    impl CloseHostfxrHandle for RecordingCloser {
+        fn close(&self, handle: Hostfxrhandle) -> i32 {
+            self.0.lock().unwrap().push(handle as usize);
+            0
+        }
+    }
+
+    #[test]
+    fn close_hostfxr_handle_on_drop() {
+        let closer = RecordingCloser(Mutex::new(Vec::new()));
+        let raw = 0x1000 as Hostfxrhandle;
+        let handle = OwnedHostfxrHandle::new(unsafe { HostfxrHandle::new_unchecked(raw) }, &closer);
+
+        assert!(closer.0.lock().unwrap().is_empty());
+        drop(handle);
+        assert_eq!(*closer.0.lock().unwrap(), vec![0x1000]);
+    }
+
+    #[test]
+    fn load_hostfxr_error_path() {
+        let path = std::env::temp_dir().join("missing").join("libhostfxr.so");
+        match Hostfxr::load_from_path(&path) {
+            Err(Error::HostfxrLoad(tried, _)) => assert_eq!(tried, path),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("loaded hostfxr from {}", path.display()),
+        }
+    }
+
+    #[test]
+    fn hostfxr_initialize_parameters_layout() {
+        // struct hostfxr_initialize_parameters { size_t size; const char_t *host_path; const char_t *dotnet_root; }
+        let pointer_size = std::mem::size_of::<usize>();
+        assert_eq!(
+            std::mem::size_of::<HostfxrInitializeParameters>(),
+            3 * pointer_size
+        );
+        assert_eq!(
+            std::mem::align_of::<HostfxrInitializeParameters>(),
+            pointer_size
+        );
+    }
+
+    #[test]
+    fn capture_hostfxr_errors() {
+        let writer: HostfxrErrorWriterFn = host_error_writer;
+        let (result, message) = capture_host_errors(|| {
+            unsafe { writer(pdcstr!("first error").as_ptr()) };
+            let (_, inner) =
+                capture_host_errors(|| unsafe { writer(pdcstr!("inner error").as_ptr()) });
+            assert_eq!(inner, "inner error");
+            unsafe { writer(pdcstr!("second error\n").as_ptr()) };
+            KnownHostExitCode::FrameworkMissingFailure
+        });
+        assert_eq!(message, "first error\nsecond error");
+
+        let err = HostExitCode::Known(result)
+            .into_result_with_message(message)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "hostfxr error: Known(FrameworkMissingFailure), first error\nsecond error"
+        );
+    }
+
+    fn pwsh_dir_root(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pwsh-host-{}-{}", test, std::process::id()))
+    }
+
+    fn fake_pwsh_dir(root: &Path, name: &str, version: &str) -> PathBuf {
+        let pwsh_dir = root.join(name);
+        fs::create_dir_all(&pwsh_dir).unwrap();
+        fs::write(pwsh_dir.join("pwsh.dll"), b"").unwrap();
+        fs::write(pwsh_dir.join("System.Management.Automation.dll"), b"").unwrap();
+        let deps_json = format!(
+            "{{\"targets\":{{\".NETCoreApp,Version=v6.0\":{{\"pwsh/{}\":{{}}}}}}}}",
+            version
+        );
+        fs::write(pwsh_dir.join("pwsh.deps.json"), deps_json).unwrap();
+        pwsh_dir
+    }
+
+    #[test]
+    fn select_pwsh_install_dir() {
+        let root = pwsh_dir_root("select");
+        let pwsh_72 = fake_pwsh_dir(&root, "7.2", "7.2.7");
+        let pwsh_73 = fake_pwsh_dir(&root, "7.3", "7.3.0-preview.8");
+        let not_pwsh = root.join("empty");
+        fs::create_dir_all(&not_pwsh).unwrap();
+
+        assert_eq!(read_pwsh_version(&pwsh_73), Some(PwshVersion::new(7, 3, 0)));
+
+        let builder = PwshRuntimeBuilder::new()
+            .search_order(&[PwshSource::InstallDir])
+            .install_dir(&not_pwsh)
+            .install_dir(&pwsh_72)
+            .install_dir(&pwsh_73);
+        assert_eq!(builder.find_pwsh_dir(), Ok(pwsh_72.clone()));
+
+        let builder = builder.min_version(PwshVersion::new(7, 3, 0));
+        assert_eq!(builder.find_pwsh_dir(), Ok(pwsh_73.clone()));
+
+        let builder = builder.max_version(PwshVersion::new(7, 3, 9));
+        assert_eq!(builder.find_pwsh_dir(), Ok(pwsh_73.clone()));
+
+        let builder = builder.min_version(PwshVersion::new(7, 4, 0));
+        assert_eq!(
+            builder.find_pwsh_dir(),
+            Err(EnvError::NoMatchingInstall(vec![
+                not_pwsh, pwsh_72, pwsh_73
+            ]))
+        );
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn read_pwsh_install() {
+        let root = pwsh_dir_root("install");
+        let pwsh_dir = fake_pwsh_dir(&root, "7-preview", "7.4.0-rc.1");
+
+        // ELF64 header up to e_machine, x86-64
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0];
+        elf.extend_from_slice(&[0; 8]);
+        elf.extend_from_slice(&[3, 0, 0x3e, 0]);
+        fs::write(pwsh_dir.join("libhostfxr.so"), elf).unwrap();
+
+        let install = PwshInstall::from_dir(&pwsh_dir).unwrap();
+        assert_eq!(install.path, pwsh_dir);
+        assert_eq!(install.version, Some(PwshVersion::new(7, 4, 0)));
+        assert!(install.preview);
+        assert_eq!(install.arch, Some(PwshArch::X64));
+
+        assert_eq!(PwshInstall::from_dir(&root), None);
+
+        let arch_of = |name: &str, library: &str, binary: Vec<u8>| {
+            let pwsh_dir = fake_pwsh_dir(&root, name, "7.4.0");
+            fs::write(pwsh_dir.join(library), binary).unwrap();
+            PwshInstall::from_dir(&pwsh_dir).unwrap().arch
+        };
+
+        // Mach-O 64-bit header up to cputype, arm64
+        let macho = vec![0xcf, 0xfa, 0xed, 0xfe, 0x0c, 0, 0, 0x01];
+        assert_eq!(
+            arch_of("macho", "libhostfxr.dylib", macho),
+            Some(PwshArch::Arm64)
+        );
+
+        // DOS header with e_lfanew at 0x3c, followed by the PE signature and Machine
+        let pe = |pe_offset: usize, machine: u16| {
+            let mut pe = vec![0u8; pe_offset + 6];
+            pe[0..2].copy_from_slice(b"MZ");
+            pe[0x3c..0x40].copy_from_slice(&(pe_offset as u32).to_le_bytes());
+            pe[pe_offset..pe_offset + 4].copy_from_slice(b"PE\0\0");
+            pe[pe_offset + 4..].copy_from_slice(&machine.to_le_bytes());
+            pe
+        };
+        assert_eq!(
+            arch_of("pe-x86", "hostfxr.dll", pe(0x80, 0x014c)),
+            Some(PwshArch::X86)
+        );
+        // a PE header past the first 512 bytes of the file
+        assert_eq!(
+            arch_of("pe-arm64", "hostfxr.dll", pe(0x400, 0xaa64)),
+            Some(PwshArch::Arm64)
+        );
+        let mut truncated = pe(0x80, 0x8664);
+        truncated.truncate(0x84);
+        assert_eq!(arch_of("pe-truncated", "hostfxr.dll", truncated), None);
+
+        // without pwsh.deps.json, the version is read from the .rsrc section of
+        // System.Management.Automation.dll, ignoring the rest of the file
+        let utf16 =
+            |text: &str| -> Vec<u8> { text.encode_utf16().flat_map(|c| c.to_le_bytes()).collect() };
+        let mut sma = pe(0x80, 0x8664);
+        sma.resize(0x400, 0);
+        sma[0x86..0x88].copy_from_slice(&2u16.to_le_bytes());
+        sma[0x94..0x96].copy_from_slice(&0x10u16.to_le_bytes());
+        for (header, name, offset) in [(0xa8, b".text\0\0\0", 0x200), (0xd0, b".rsrc\0\0\0", 0x300)]
+        {
+            sma[header..header + 8].copy_from_slice(name);
+            sma[header + 16..header + 20].copy_from_slice(&0x100u32.to_le_bytes());
+            sma[header + 20..header + 24].copy_from_slice(&(offset as u32).to_le_bytes());
+        }
+        let decoy = utf16("ProductVersion\u{0}9.9.9");
+        sma[0x200..0x200 + decoy.len()].copy_from_slice(&decoy);
+        let version = utf16("ProductVersion\u{0}\u{0}7.4.1 SHA: 1234abcd");
+        sma[0x310..0x310 + version.len()].copy_from_slice(&version);
+        let sma_dir = fake_pwsh_dir(&root, "sma-version", "7.4.0");
+        fs::remove_file(sma_dir.join("pwsh.deps.json")).unwrap();
+        fs::write(sma_dir.join("System.Management.Automation.dll"), sma).unwrap();
+        assert_eq!(read_pwsh_version(&sma_dir), Some(PwshVersion::new(7, 4, 1)));
+
+        // the same install reached through different paths is listed once
+        let installs = read_pwsh_installs(vec![
+            pwsh_dir.clone(),
+            pwsh_dir.join("."),
+            root.join("missing"),
+            root.join("macho"),
+        ]);
+        assert_eq!(
+            installs.iter().map(|i| &i.path).collect::<Vec<_>>(),
+            vec![&pwsh_dir, &root.join("macho")]
+        );
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_pwsh_links() {
+        use std::os::unix::fs::symlink;
+
+        let root = pwsh_dir_root("links");
+        let pwsh_dir = fake_pwsh_dir(&root, "lib/powershell", "7.2.7");
+        fs::write(pwsh_dir.join("pwsh"), b"").unwrap();
+        let pwsh_dir = fs::canonicalize(pwsh_dir).unwrap();
+
+        // /usr/bin/pwsh -> ../lib/powershell/pwsh
+        fs::create_dir_all(root.join("bin")).unwrap();
+        symlink("../lib/powershell/pwsh", root.join("bin/pwsh")).unwrap();
+        assert_eq!(
+            resolve_pwsh_dir(&root.join("bin/pwsh")),
+            Some(pwsh_dir.clone())
+        );
+
+        // /usr/bin/pwsh -> /etc/alternatives/pwsh -> /usr/lib/powershell/pwsh
+        fs::create_dir_all(root.join("alternatives")).unwrap();
+        fs::create_dir_all(root.join("sbin")).unwrap();
+        symlink(pwsh_dir.join("pwsh"), root.join("alternatives/pwsh")).unwrap();
+        symlink("../alternatives/pwsh", root.join("sbin/pwsh")).unwrap();
+        assert_eq!(resolve_pwsh_dir(&root.join("sbin/pwsh")), Some(pwsh_dir));
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn resolve_pwsh_tool_shim() {
+        let root = pwsh_dir_root("tools");
+        let store = root.join(".store");
+        let tool_dir = |package: &str, version: &str| {
+            format!(
+                "{}/{}/{}/{}/tools/net7.0/any",
+                package, version, package, version
+            )
+        };
+        fake_pwsh_dir(&store, &tool_dir("powershell", "7.2.7"), "7.2.7");
+        let stable = fake_pwsh_dir(&store, &tool_dir("powershell", "7.3.0"), "7.3.0");
+        let preview = fake_pwsh_dir(
+            &store,
+            &tool_dir("powershell.preview", "7.4.0-preview.1"),
+            "7.4.0-preview.1",
+        );
+        fs::write(root.join("pwsh"), b"").unwrap();
+
+        let resolved = resolve_pwsh_dir(&root.join("pwsh")).unwrap();
+        assert_eq!(resolved, fs::canonicalize(stable).unwrap());
+        assert_eq!(
+            PwshInstall::from_dir(&preview).map(|install| install.preview),
+            Some(true)
+        );
+
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn invoke_pwsh_error_record() {
+        let pwsh = PowerShell::new().unwrap();
+
+        // throw "boom"
+        pwsh.add_script("throw \"boom\"").unwrap();
+        let result = pwsh.invoke(true);
+
+        match result {
+            Err(Error::PowerShell(err)) => {
+                assert_eq!(err.message, "boom");
+                assert_eq!(err.fully_qualified_error_id.as_deref(), Some("boom"));
+            }
+            _ => panic!("expected a PowerShell error"),
+        }
+
+        // Get-Item -Path <missing>; 'after'
+        pwsh.add_command("Get-Item").unwrap();
+        pwsh.add_parameter_string("-Path", "/pwsh-host-rs/missing")
+            .unwrap();
+        pwsh.add_statement().unwrap();
+        pwsh.add_script("'after'").unwrap();
+        let output = pwsh.invoke(false).unwrap();
+
+        // a non-terminating error keeps the output and is left in the error stream
+        assert_eq!(output.len(), 1);
+        let errors = pwsh.errors().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].fully_qualified_error_id.as_deref(),
+            Some("PathNotFound,Microsoft.PowerShell.Commands.GetItemCommand")
+        );
+    }
+
+    #[test]
+    fn invoke_pwsh_streams() {
+        let pwsh = PowerShell::new().unwrap();
+
+        pwsh.add_script(
+            "Write-Warning 'careful'; \
+             Write-Verbose 'chatty' -Verbose; \
+             Write-Information 'hello' -Tags 'greeting'; \
+             Write-Progress -Activity 'Working' -Status 'Half' -PercentComplete 50; \
+             'done'",
+        )
+        .unwrap();
+        let output = pwsh.invoke(false).unwrap();
+        assert_eq!(output.len(), 1);
+
+        let warnings = pwsh.warnings().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "careful");
+
+        let verbose = pwsh.verbose().unwrap();
+        assert_eq!(verbose.len(), 1);
+        assert_eq!(verbose[0].message, "chatty");
+
+        let information = pwsh.information().unwrap();
+        assert_eq!(information.len(), 1);
+        assert_eq!(information[0].message_data, "hello");
+        assert_eq!(information[0].tags, vec!["greeting".to_string()]);
+
+        let progress = pwsh.progress().unwrap();
+        assert!(!progress.is_empty());
+        assert_eq!(progress[0].activity, "Working");
+        assert_eq!(progress[0].percent_complete, 50);
+
+        pwsh.clear_streams().unwrap();
+        assert!(pwsh.warnings().unwrap().is_empty());
+        assert!(pwsh.progress().unwrap().is_empty());
+    }
+
+    #[test]
+    fn invoke_pwsh_stream_callbacks() {
+        let pwsh = PowerShell::new().unwrap();
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        {
+            let output = output.clone();
+            pwsh.on_output(move |obj| output.lock().unwrap().push(obj.unwrap().clone()))
+                .unwrap();
+            let progress = progress.clone();
+            pwsh.on_progress(move |record| {
+                progress
+                    .lock()
+                    .unwrap()
+                    .push(record.unwrap().percent_complete)
+            })
+            .unwrap();
+        }
+
+        pwsh.add_script(
+            "1..3 | ForEach-Object { \
+                Write-Progress -Activity 'Counting' -PercentComplete ($_ * 25); $_ \
+             }",
+        )
+        .unwrap();
+        let results = pwsh.invoke(true).unwrap();
+
+        assert_eq!(output.lock().unwrap().len(), results.len());
+        assert_eq!(progress.lock().unwrap()[..3], [25, 50, 75]);
+    }
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn invoke_pwsh_async() {
+        let pwsh = PowerShell::new().unwrap();
+
+        pwsh.add_script("Start-Sleep -Milliseconds 100; 'first'; 'second'")
+            .unwrap();
+        let future = pwsh.invoke_async().unwrap();
+        let output = block_on(future).unwrap();
+        pwsh.clear().unwrap();
+        assert_eq!(output.len(), 2);
+
+        pwsh.add_script("throw 'async boom'").unwrap();
+        let future = pwsh.invoke_async().unwrap();
+        match block_on(future) {
+            Err(Error::PowerShell(err)) => assert_eq!(err.message, "async boom"),
+            _ => panic!("expected a PowerShell error"),
+        }
+
+        // the future keeps the pipeline alive once the PowerShell is dropped
+        let pwsh = PowerShell::new().unwrap();
+        pwsh.on_output(|_| {}).unwrap();
+        pwsh.add_script("Start-Sleep -Milliseconds 200; 'detached'")
+            .unwrap();
+        let future = pwsh.invoke_async().unwrap();
+        drop(pwsh);
+        let output = block_on(future).unwrap();
+        assert_eq!(output[0].value.as_ref().unwrap().as_str(), Some("detached"));
+    }
+
+    #[test]
+    fn invoke_pwsh_stop() {
+        let pwsh = PowerShell::new().unwrap();
+
+        pwsh.add_script("Start-Sleep -Seconds 30").unwrap();
+        let result = pwsh.invoke_with_timeout(Duration::from_millis(200));
+        assert!(matches!(result, Err(Error::Timeout(_))));
+        pwsh.clear().unwrap();
+
+        let stop_handle = pwsh.stop_handle();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            stop_handle.stop().unwrap();
+        });
+        pwsh.add_script("Start-Sleep -Seconds 30").unwrap();
+        let result = pwsh.invoke(true);
+        stopper.join().unwrap();
+        assert!(matches!(result, Err(Error::PowerShell(_))));
+    }
+
+    #[test]
+    fn invoke_pwsh_typed_parameters() {
+        let pwsh = PowerShell::new().unwrap();
+
+        pwsh.add_command("Write-Output").unwrap();
+        pwsh.add_parameter("-InputObject", true).unwrap();
+        let output = pwsh.invoke(true).unwrap();
+        assert_eq!(output[0].value.as_ref().unwrap().as_bool(), Some(true));
+
+        let guid = Uuid::parse_str("792e5b37-4505-47ef-b7d2-8711bb7affa8").unwrap();
+        pwsh.add_command("Write-Output").unwrap();
+        pwsh.add_argument(guid).unwrap();
+        let output = pwsh.invoke(true).unwrap();
+        assert_eq!(output[0].value.as_ref().unwrap().as_guid(), Some(&guid));
+
+        pwsh.add_command("Write-Output").unwrap();
+        pwsh.add_argument(vec![1u8, 2, 3, 4]).unwrap();
+        let output = pwsh.invoke(true).unwrap();
+        assert_eq!(
+            output[0].value.as_ref().unwrap().as_bytes(),
+            Some(&vec![1u8, 2, 3, 4])
+        );
+
+        pwsh.add_command("Write-Output").unwrap();
+        pwsh.add_argument(Duration::from_millis(1500)).unwrap();
+        let output = pwsh.invoke(true).unwrap();
+        assert_eq!(
+            output[0].value.as_ref().unwrap().as_duration(),
+            Some(&Duration::from_millis(1500))
+        );
+
+        pwsh.add_command("ForEach-Object").unwrap();
+        pwsh.add_parameter("-Process", CliScriptBlock::new(None, "'from script block'"))
+            .unwrap();
+        pwsh.add_parameter("-InputObject", 1).unwrap();
+        let output = pwsh.invoke(true).unwrap();
+        assert_eq!(
+            output[0].value.as_ref().unwrap().as_str(),
+            Some("from script block")
+        );
+    }
+
+    #[test]
+    fn invoke_pwsh_complex_parameters() {
+        let pwsh = PowerShell::new().unwrap();
+
+        // Get-ChildItem -Path / -Force | Measure-Object
+        pwsh.add_command("Get-ChildItem").unwrap();
+        pwsh.add_parameter("-Path", "/").unwrap();
+        pwsh.add_switch("-Force").unwrap();
+        pwsh.add_command("Measure-Object").unwrap();
+        let output = pwsh.invoke(true).unwrap();
+        assert!(!output.is_empty());
+
+        // ($list | Measure-Object -Sum).Sum
+        pwsh.add_script("param($Numbers) ($Numbers | Measure-Object -Sum).Sum")
+            .unwrap();
+        pwsh.add_parameter("-Numbers", CliList::from(vec![1, 2, 3]))
+            .unwrap();
+        let output = pwsh.invoke(true).unwrap();
+        assert_eq!(output[0].value.as_ref().unwrap().as_double(), Some(6.0));
+
+        // & { param($Name, $Settings, $Person) ... } @parameters
+        pwsh.add_script(
+            "param($Name, $Settings, $Person) \
+             \"$Name $($Settings['Verbose']) $($Person.Name)\"",
+        )
+        .unwrap();
+        let mut settings = HashMap::new();
+        settings.insert("Verbose", true);
+        let person = CliObject::new(
+            None,
+            vec![CliValue::from("Alice").with_name("Name")],
+            None,
+            vec![],
+            None,
+        );
+        pwsh.add_parameters(vec![
+            ("Name", CliValue::from("splat")),
+            ("Settings", CliValue::from(settings)),
+            ("Person", CliValue::from(person)),
+        ])
+        .unwrap();
+        let output = pwsh.invoke(true).unwrap();
+        assert_eq!(
+            output[0].value.as_ref().unwrap().as_str(),
+            Some("splat True Alice")
+        );
+
+        assert!(matches!(pwsh.add_script("'a\0b'"), Err(Error::CString(_))));
+        let script_blocks = CliList::new(None, vec![CliScriptBlock::new(None, "$_").into()]);
+        assert!(matches!(
+            pwsh.add_argument(script_blocks),
+            Err(Error::NestedScriptBlock)
+        ));
+    }
+
+    #[test]
+    fn test_cli_xml_serialize_complex() {
+        assert_eq!(
+            CliValue::from(CliList::from(vec![1, 2])).to_cli_xml(),
+            r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04"><Obj RefId="0"><TN RefId="1"><T>System.Object[]</T><T>System.Array</T><T>System.Object</T></TN><LST><I32>1</I32><I32>2</I32></LST></Obj></Objs>"#
+        );
+        assert_eq!(
+            CliValue::from(CliDictionary::from(vec![("Name", "pwsh")])).to_cli_xml(),
+            r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04"><Obj RefId="0"><TN RefId="1"><T>System.Collections.Hashtable</T><T>System.Object</T></TN><DCT><En><S N="Key">Name</S><S N="Value">pwsh</S></En></DCT></Obj></Objs>"#
+        );
+        let obj = CliObject::new(
+            None,
+            vec![CliValue::from(42).with_name("Answer")],
+            None,
+            vec![],
+            None,
+        );
+        assert_eq!(
+            CliValue::from(obj).to_cli_xml(),
+            r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04"><Obj RefId="0"><TN RefId="1"><T>System.Management.Automation.PSCustomObject</T><T>System.Object</T></TN><MS><I32 N="Answer">42</I32></MS></Obj></Objs>"#
+        );
+    }
+
+    #[test]
+    fn test_cli_xml_serialize() {
+        let values: Vec<CliValue> = vec![
+            "This is a string".into(),
+            'é'.into(),
+            false.into(),
+            (-12i8).into(),
+            65535u16.into(),
+            i64::MIN.into(),
+            1.5f32.into(),
+            Uuid::nil().into(),
+            Duration::from_secs(90).into(),
+            vec![1u8, 2, 3, 4].into(),
+        ];
+        for value in values {
+            let xml = value.to_cli_xml();
+            let objs = parse_cli_xml(&xml).unwrap();
+            assert_eq!(objs.len(), 1, "{}", xml);
+            assert_eq!(
+                format!("{:?}", objs[0].value.as_ref().unwrap()),
+                format!("{:?}", value),
+                "{}",
+                xml
+            );
+        }
+
+        // text escaped by the writer is decoded back by the parser
+        let values: Vec<CliValue> = vec![
+            "a < b && c > d".into(),
+            "line 1\r\nline 2\ttab".into(),
+            "_x000A_ _x005F_ _x_ _".into(),
+            "\u{0}\u{1F}😀".into(),
+            '&'.into(),
+            '\n'.into(),
+            CliScriptBlock::new(None, "if ($a -lt 1 -and $b) { \"<$_>\" }").into(),
+        ];
+        for value in values {
+            let xml = value.to_cli_xml();
+            let objs = parse_cli_xml(&xml).unwrap();
+            assert_eq!(
+                format!("{:?}", objs[0].value.as_ref().unwrap()),
+                format!("{:?}", value),
+                "{}",
+                xml
+            );
+        }
+
+        // a char outside of the BMP does not fit in a .NET char
+        assert_eq!(
+            CliValue::from('😀').to_cli_xml(),
+            r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04"><S>😀</S></Objs>"#
+        );
+        let script_block = CliValue::from(CliScriptBlock::new(None, "$_"));
+        assert!(!CliValue::from(CliList::from(vec![1, 2])).contains_script_block());
+        assert!(CliValue::from(CliList::new(None, vec![script_block])).contains_script_block());
+
+        assert_eq!(
+            CliValue::from("a < b & _x000A_\r\n").to_cli_xml(),
+            r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04"><S>a &lt; b &amp; _x005F_x000A__x000D__x000A_</S></Objs>"#
+        );
+        assert_eq!(
+            CliValue::from(CliInt32::new(Some("Count"), 3)).to_cli_xml(),
+            r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04"><I32 N="Count">3</I32></Objs>"#
+        );
+        assert_eq!(
+            CliValue::from(Option::<bool>::None).to_cli_xml(),
+            r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04"><Nil /></Objs>"#
+        );
+    }
+
+    fn managed_heap_size(pwsh: &PowerShell) -> i64 {
+        pwsh.add_script("[System.GC]::GetTotalMemory($true)")
+            .unwrap();
+        let output = pwsh.invoke(true).unwrap();
+        output[0].value.as_ref().unwrap().as_i64().unwrap()
+    }
+
+    #[test]
+    fn create_drop_pwsh() {
+        let monitor = PowerShell::new().unwrap();
+
+        // warm up, so that one-time allocations are not counted
+        for _ in 0..5 {
+            let pwsh = PowerShell::new().unwrap();
+            pwsh.add_script("'warm up'").unwrap();
+            pwsh.invoke(true).unwrap();
+        }
+        let heap_before = managed_heap_size(&monitor);
+
+        for _ in 0..50 {
+            let pwsh = PowerShell::new().unwrap();
+            pwsh.on_output(|_| {}).unwrap();
+            pwsh.add_script("'hello'").unwrap();
+            pwsh.invoke(true).unwrap();
+        }
+        let heap_after = managed_heap_size(&monitor);
+
+        // each leaked instance keeps a whole runspace alive, which is
+        // several megabytes, so 50 of them would grow the heap noticeably
+        let growth = heap_after - heap_before;
+        assert!(
+            growth < 4 * 1024 * 1024,
+            "managed heap grew by {} bytes",
+            growth
+        );
+    }
+
+    #[test]
+    fn drop_pwsh_with_stop_handle() {
+        let pwsh = PowerShell::new().unwrap();
+        let stop_handle = pwsh.stop_handle();
+        drop(pwsh);
+        stop_handle.stop().unwrap();
+    }
+
+    #[test]
+    fn stream_callback_panic_and_reentrancy() {
+        // ids handed out by PowerShell::new start at 1 and never reach this one
+        let id = usize::MAX;
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let callback_messages = messages.clone();
+        stream_callbacks().entry(id).or_default().warning = Some(Arc::new(Mutex::new(
+            move |record: Result<&WarningRecord, &Error>| {
+                callback_messages
+                    .lock()
+                    .unwrap()
+                    .push(record.unwrap().message.clone());
+                // registering callbacks from a callback must not deadlock
+                stream_callbacks().entry(id).or_default().verbose = None;
+                panic!("panic in a stream callback");
+            },
+        )));
+
+        let xml = CString::new(
+            r#"<Objs><Obj RefId="0"><Props><S N="Message">careful</S></Props></Obj></Objs>"#,
+        )
+        .unwrap();
+        let context = id as *mut libc::c_void;
+        unsafe {
+            stream_callback(context, PowerShellStream::Warning, xml.as_ptr());
+            stream_callback(context, PowerShellStream::Warning, xml.as_ptr());
+        }
+        stream_callbacks().remove(&id);
+        // late callbacks for a dropped PowerShell are ignored
+        unsafe { stream_callback(context, PowerShellStream::Warning, xml.as_ptr()) };
+
+        assert_eq!(*messages.lock().unwrap(), vec!["careful", "careful"]);
+
+        // malformed output is passed to the callback instead of being dropped
+        let errors = Arc::new(Mutex::new(0));
+        let callback_errors = errors.clone();
+        stream_callbacks().entry(id).or_default().output = Some(Arc::new(Mutex::new(
+            move |obj: Result<&CliObject, &Error>| {
+                if let Err(Error::CliXml(..)) = obj {
+                    *callback_errors.lock().unwrap() += 1;
+                }
+            },
+        )));
+        let xml = CString::new(r#"<Objs><I32>forty-two</I32></Objs>"#).unwrap();
+        unsafe { stream_callback(context, PowerShellStream::Output, xml.as_ptr()) };
+        stream_callbacks().remove(&id);
+        assert_eq!(*errors.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_cli_xml_stream_records() {
+        let warning = WarningRecord::from_cli_xml(
+            r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04">
+  <Obj RefId="0">
+    <TN RefId="0">
+      <T>System.Management.Automation.WarningRecord</T>
+      <T>System.Management.Automation.InformationalRecord</T>
+      <T>System.Object</T>
+    </TN>
+    <ToString>careful</ToString>
+    <Props>
+      <S N="Message">careful</S>
+      <Nil N="FullyQualifiedWarningId" />
+    </Props>
+  </Obj>
+</Objs>"#,
+        )
+        .unwrap();
+        assert_eq!(warning.message, "careful");
+
+        let progress = ProgressRecord::from_cli_xml(
+            r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04">
+  <Obj RefId="0">
+    <TN RefId="0">
+      <T>System.Management.Automation.ProgressRecord</T>
+      <T>System.Object</T>
+    </TN>
+    <ToString>parent = -1 id = 0 act = Working stat = Half cur =  pct = 50 sec = -1 type = Processing</ToString>
+    <Props>
+      <I32 N="ActivityId">0</I32>
+      <I32 N="ParentActivityId">-1</I32>
+      <S N="Activity">Working</S>
+      <S N="StatusDescription">Half</S>
+      <Nil N="CurrentOperation" />
+      <I32 N="PercentComplete">50</I32>
+      <I32 N="SecondsRemaining">-1</I32>
+      <Obj N="RecordType" RefId="1">
+        <TN RefId="1">
+          <T>System.Management.Automation.ProgressRecordType</T>
+          <T>System.Enum</T>
+          <T>System.ValueType</T>
+          <T>System.Object</T>
+        </TN>
+        <ToString>Processing</ToString>
+        <I32>0</I32>
+      </Obj>
+    </Props>
+  </Obj>
+</Objs>"#,
+        )
+        .unwrap();
+        assert_eq!(progress.activity, "Working");
+        assert_eq!(progress.status_description, "Half");
+        assert_eq!(progress.current_operation, None);
+        assert_eq!(progress.percent_complete, 50);
+        assert_eq!(progress.record_type.as_deref(), Some("Processing"));
+        assert!(!progress.is_completed());
+    }
+
+    #[test]
+    fn test_cli_xml_error_record() {
+        // try { Invoke-Boom } catch { [System.Management.Automation.PSSerializer]::Serialize($_) }
+        let error_xml = r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04">
+  <Obj RefId="0">
+    <TN RefId="0">
+      <T>System.Management.Automation.ErrorRecord</T>
+      <T>System.Object</T>
+    </TN>
+    <ToString>boom</ToString>
+    <MS>
+      <Obj N="Exception" RefId="1">
+        <TN RefId="1">
+          <T>System.Management.Automation.RuntimeException</T>
+          <T>System.SystemException</T>
+          <T>System.Exception</T>
+          <T>System.Object</T>
+        </TN>
+        <ToString>System.Management.Automation.RuntimeException: boom</ToString>
+        <Props>
+          <S N="ErrorRecord">boom</S>
+          <B N="WasThrownFromThrowStatement">true</B>
+          <Nil N="TargetSite" />
+          <S N="Message">boom</S>
+          <Nil N="InnerException" />
+          <Nil N="HelpLink" />
+          <Nil N="Source" />
+          <I32 N="HResult">-2146233087</I32>
+          <Nil N="StackTrace" />
+        </Props>
+      </Obj>
+      <S N="TargetObject">boom</S>
+      <S N="FullyQualifiedErrorId">boom</S>
+      <Nil N="InvocationInfo" />
+      <I32 N="ErrorCategory_Category">14</I32>
+      <S N="ErrorCategory_Activity"></S>
+      <S N="ErrorCategory_Reason">RuntimeException</S>
+      <S N="ErrorCategory_TargetName">boom</S>
+      <S N="ErrorCategory_TargetType">String</S>
+      <S N="ErrorCategory_Message">OperationStopped: (boom:String) [], RuntimeException</S>
+      <B N="SerializeExtendedInfo">false</B>
+      <S N="ErrorDetails_ScriptStackTrace">at Invoke-Boom, /tmp/boom.ps1: line 2_x000D__x000A_at &lt;ScriptBlock&gt;, &lt;No file&gt;: line 1</S>
+    </MS>
+  </Obj>
+</Objs>"#;
+
+        let err = ErrorRecord::from_cli_xml(error_xml).unwrap();
+
+        assert_eq!(err.message, "boom");
+        assert_eq!(err.fully_qualified_error_id.as_deref(), Some("boom"));
+        assert_eq!(err.category_info.category, 14);
+        assert_eq!(
+            err.category_info.reason.as_deref(),
+            Some("RuntimeException")
+        );
+        assert_eq!(err.category_info.target_name.as_deref(), Some("boom"));
+        assert_eq!(
+            err.category_info.message.as_deref(),
+            Some("OperationStopped: (boom:String) [], RuntimeException")
+        );
+        assert_eq!(
+            err.script_stack_trace.as_deref(),
+            Some("at Invoke-Boom, /tmp/boom.ps1: line 2\r\nat <ScriptBlock>, <No file>: line 1")
+        );
+        assert_eq!(
+            err.exception_type.as_deref(),
+            Some("System.Management.Automation.RuntimeException")
+        );
+
+        assert!(matches!(
+            ErrorRecord::from_cli_xml(r#"<Objs><Obj RefId="0"><I32>boom</I32></Obj></Objs>"#),
+            Err(Error::CliXml(..))
+        ));
+    }
+
+    #[test]
+    fn test_cli_xml_top_level_primitives() {
+        // "Compare-Object", "Group-Object", 42 | Export-Clixml
+        let objs_xml = r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04">
+  <S>Compare-Object</S>
+  <S>Group-Object</S>
+  <I32>42</I32>
+</Objs>"#;
+
+        let objs: Vec<CliObject> = parse_cli_xml(objs_xml).unwrap();
+        assert_eq!(objs.len(), 3);
+
+        let first_val = objs[0].value.as_deref().unwrap();
+        assert_eq!(first_val.as_str(), Some("Compare-Object"));
+
+        let second_val = objs[1].value.as_deref().unwrap();
+        assert_eq!(second_val.as_str(), Some("Group-Object"));
+
+        let third_val = objs[2].value.as_deref().unwrap();
+        assert_eq!(third_val.as_i32(), Some(42));
+    }
+
+    #[test]
+    fn test_cli_xml_invalid() {
+        let unknown_xml =
+            r#"<Objs><Obj RefId="0"><Unknown>x</Unknown><MS><I32 N="A">1</I32></MS></Obj></Objs>"#;
+        let objs = parse_cli_xml(unknown_xml).unwrap();
+        assert_eq!(objs[0].get_i32("A"), Ok(1));
+
+        let invalid_value_xml = r#"<Objs><I32>forty-two</I32></Objs>"#;
+        assert!(matches!(
+            parse_cli_xml(invalid_value_xml),
+            Err(Error::CliXml(_, message)) if message == "invalid <I32> value \"forty-two\""
+        ));
+
+        let malformed_xml = r#"<Objs><S>a</I32></Objs>"#;
+        assert!(matches!(
+            parse_cli_xml(malformed_xml),
+            Err(Error::CliXml(..))
+        ));
+    }
+
+    #[test]
+    fn test_cli_xml_escaped_text() {
+        // [System.Management.Automation.PSSerializer]::Serialize("a < b & c`r`n_x000A_")
+        let escaped_xml = r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04"><S>a &lt; b &amp; c_x000D__x000A__x005F_x000A_</S></Objs>"#;
+        let objs = parse_cli_xml(escaped_xml).unwrap();
+        assert_eq!(
+            objs[0].value.as_ref().unwrap().as_str(),
+            Some("a < b & c\r\n_x000A_")
+        );
+
+        let to_string_xml = r#"<Objs><Obj RefId="0"><ToString>at &lt;ScriptBlock&gt;, &lt;No file&gt;: line 1_x000D__x000A_</ToString><S>_xD83D__xDE00_ _x004_</S></Obj></Objs>"#;
+        let objs = parse_cli_xml(to_string_xml).unwrap();
+        assert_eq!(
+            objs[0].string_repr.as_deref(),
+            Some("at <ScriptBlock>, <No file>: line 1\r\n")
+        );
+        assert_eq!(objs[0].value.as_ref().unwrap().as_str(), Some("😀 _x004_"));
     }
 
     /*
@@ -132,76 +1172,76 @@ mod pwsh {
     </Obj>
 </Objs>"#;
 
-        let objs: Vec<CliObject> = parse_cli_xml(obj_xml);
+        let objs: Vec<CliObject> = parse_cli_xml(obj_xml).unwrap();
 
         let obj = objs.get(0).unwrap();
 
-        let string_prop = obj.values.get(0).unwrap();
+        let string_prop = obj.extended_properties.get(0).unwrap();
         assert!(string_prop.is_string());
         assert_eq!(string_prop.as_str(), Some("Purée"));
 
-        let char_prop = obj.values.get(1).unwrap();
+        let char_prop = obj.extended_properties.get(1).unwrap();
         assert!(char_prop.is_char());
         assert_eq!(char_prop.as_char(), Some('à'));
 
-        let bool_prop = obj.values.get(2).unwrap();
+        let bool_prop = obj.extended_properties.get(2).unwrap();
         assert!(bool_prop.is_bool());
         assert_eq!(bool_prop.as_bool(), Some(true));
 
-        let datetime_prop = obj.values.get(3).unwrap();
+        let datetime_prop = obj.extended_properties.get(3).unwrap();
         assert!(datetime_prop.is_datetime());
 
-        let duration_prop = obj.values.get(4).unwrap();
+        let duration_prop = obj.extended_properties.get(4).unwrap();
         assert!(duration_prop.is_duration());
 
-        let uint8_prop = obj.values.get(5).unwrap();
+        let uint8_prop = obj.extended_properties.get(5).unwrap();
         assert!(uint8_prop.is_uint8());
         assert_eq!(uint8_prop.as_u8(), Some(254));
 
-        let int8_prop = obj.values.get(6).unwrap();
+        let int8_prop = obj.extended_properties.get(6).unwrap();
         assert!(int8_prop.is_int8());
         assert_eq!(int8_prop.as_i8(), Some(-127));
 
-        let uint16_prop = obj.values.get(7).unwrap();
+        let uint16_prop = obj.extended_properties.get(7).unwrap();
         assert!(uint16_prop.is_uint16());
         assert_eq!(uint16_prop.as_u16(), Some(65535));
 
-        let int16_prop = obj.values.get(8).unwrap();
+        let int16_prop = obj.extended_properties.get(8).unwrap();
         assert!(int16_prop.is_int16());
         assert_eq!(int16_prop.as_i16(), Some(-32767));
 
-        let uint32_prop = obj.values.get(9).unwrap();
+        let uint32_prop = obj.extended_properties.get(9).unwrap();
         assert!(uint32_prop.is_uint32());
         assert_eq!(uint32_prop.as_u32(), Some(4294967295));
 
-        let int32_prop = obj.values.get(10).unwrap();
+        let int32_prop = obj.extended_properties.get(10).unwrap();
         assert!(int32_prop.is_int32());
         assert_eq!(int32_prop.as_i32(), Some(-2147483648));
 
-        let uint64_prop = obj.values.get(11).unwrap();
+        let uint64_prop = obj.extended_properties.get(11).unwrap();
         assert!(uint64_prop.is_uint64());
         assert_eq!(uint64_prop.as_u64(), Some(18446744073709551615));
 
-        let int64_prop = obj.values.get(12).unwrap();
+        let int64_prop = obj.extended_properties.get(12).unwrap();
         assert!(int64_prop.is_int64());
         assert_eq!(int64_prop.as_i64(), Some(-9223372036854775808));
 
-        let float_prop = obj.values.get(13).unwrap();
+        let float_prop = obj.extended_properties.get(13).unwrap();
         assert!(float_prop.is_float());
         assert_eq!(float_prop.as_float(), Some(12.34));
 
-        let double_prop = obj.values.get(14).unwrap();
+        let double_prop = obj.extended_properties.get(14).unwrap();
         assert!(double_prop.is_double());
         assert_eq!(double_prop.as_double(), Some(34.56));
 
-        let decimal_prop = obj.values.get(15).unwrap();
+        let decimal_prop = obj.extended_properties.get(15).unwrap();
         assert!(decimal_prop.is_decimal());
 
-        let buffer_prop = obj.values.get(16).unwrap();
+        let buffer_prop = obj.extended_properties.get(16).unwrap();
         assert!(buffer_prop.is_buffer());
         assert_eq!(buffer_prop.as_bytes(), Some(vec![1, 2, 3, 4u8].as_ref()));
 
-        let guid_prop = obj.values.get(17).unwrap();
+        let guid_prop = obj.extended_properties.get(17).unwrap();
         assert!(guid_prop.is_guid());
         assert_eq!(
             guid_prop.as_guid(),
@@ -210,35 +1250,183 @@ mod pwsh {
                 .as_ref()
         );
 
-        let uri_prop = obj.values.get(18).unwrap();
+        let uri_prop = obj.extended_properties.get(18).unwrap();
         assert!(uri_prop.is_uri());
         assert_eq!(
             uri_prop.as_uri(),
             url::Url::parse("http://www.microsoft.com/").ok().as_ref()
         );
 
-        let version_prop = obj.values.get(19).unwrap();
+        let version_prop = obj.extended_properties.get(19).unwrap();
         assert!(version_prop.is_version());
         assert_eq!(version_prop.as_version(), Some("6.2.1.3"));
 
-        let xml_document_prop = obj.values.get(20).unwrap();
+        let xml_document_prop = obj.extended_properties.get(20).unwrap();
         assert!(xml_document_prop.is_xml_document());
         assert_eq!(
             xml_document_prop.as_xml_document(),
-            Some("&lt;item&gt;&lt;name&gt;laptop&lt;/name&gt;&lt;/item&gt;")
+            Some("<item><name>laptop</name></item>")
         );
 
-        let script_block_prop = obj.values.get(21).unwrap();
+        let script_block_prop = obj.extended_properties.get(21).unwrap();
         assert!(script_block_prop.is_script_block());
         assert_eq!(
             script_block_prop.as_script_block(),
             Some("Get-Command -Type Cmdlet")
         );
 
-        let null_prop = obj.values.get(22).unwrap();
+        let null_prop = obj.extended_properties.get(22).unwrap();
         assert!(null_prop.is_null());
     }
 
+    #[test]
+    fn test_cli_xml_references() {
+        // $a = [PSCustomObject]@{ Name = 'a' }; $a, [PSCustomObject]@{ Name = 'b'; Other = $a }
+        let refs_xml = r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04">
+  <Obj RefId="0">
+    <TN RefId="0">
+      <T>System.Management.Automation.PSCustomObject</T>
+      <T>System.Object</T>
+    </TN>
+    <MS>
+      <S N="Name">a</S>
+    </MS>
+  </Obj>
+  <Obj RefId="1">
+    <TNRef RefId="0" />
+    <MS>
+      <S N="Name">b</S>
+      <Ref N="Other" RefId="0" />
+    </MS>
+  </Obj>
+</Objs>"#;
+
+        let objs = parse_cli_xml(refs_xml).unwrap();
+        assert_eq!(objs.len(), 2);
+        assert_eq!(objs[1].type_names, objs[0].type_names);
+        assert_eq!(
+            objs[1].type_names,
+            vec![
+                "System.Management.Automation.PSCustomObject",
+                "System.Object"
+            ]
+        );
+
+        let other = &objs[1].extended_properties[1];
+        assert!(other.is_ref());
+        assert_eq!(other.get_name(), Some("Other"));
+        assert_eq!(other.as_ref_id(), Some("0"));
+
+        let resolved = match other {
+            CliValue::CliRef(other) => other.resolve(&objs).unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(resolved.extended_properties[0].as_str(), Some("a"));
+        assert!(find_cli_object(&objs, "2").is_none());
+    }
+
+    #[test]
+    fn test_cli_xml_collections() {
+        // @{ Name = 'a'; Items = 1, 2 }
+        // $s = [Collections.Stack]::new(); $s.Push(1); $s.Push(2); $s
+        // $q = [Collections.Queue]::new(); $q.Enqueue('x'); $q.Enqueue('y'); ,$q
+        let collections_xml = r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04">
+  <Obj RefId="0">
+    <TN RefId="0">
+      <T>System.Collections.Hashtable</T>
+      <T>System.Object</T>
+    </TN>
+    <DCT>
+      <En>
+        <S N="Key">Name</S>
+        <S N="Value">a</S>
+      </En>
+      <En>
+        <S N="Key">Items</S>
+        <Obj N="Value" RefId="1">
+          <TN RefId="1">
+            <T>System.Object[]</T>
+            <T>System.Array</T>
+            <T>System.Object</T>
+          </TN>
+          <LST>
+            <I32>1</I32>
+            <I32>2</I32>
+          </LST>
+        </Obj>
+      </En>
+    </DCT>
+  </Obj>
+  <Obj RefId="2">
+    <TN RefId="2">
+      <T>System.Collections.Stack</T>
+      <T>System.Object</T>
+    </TN>
+    <STK>
+      <I32>2</I32>
+      <I32>1</I32>
+    </STK>
+  </Obj>
+  <Obj RefId="3">
+    <TN RefId="3">
+      <T>System.Collections.Queue</T>
+      <T>System.Object</T>
+    </TN>
+    <QUE>
+      <S>x</S>
+      <S>y</S>
+    </QUE>
+  </Obj>
+</Objs>"#;
+
+        let objs = parse_cli_xml(collections_xml).unwrap();
+        assert_eq!(objs.len(), 3);
+
+        let entries = objs[0].value.as_ref().unwrap().as_dictionary().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0.as_str(), Some("Name"));
+        assert_eq!(entries[0].0.get_name(), None);
+        assert_eq!(entries[0].1.as_str(), Some("a"));
+        assert_eq!(entries[1].0.as_str(), Some("Items"));
+        let items = entries[1]
+            .1
+            .as_object()
+            .unwrap()
+            .value
+            .as_ref()
+            .unwrap()
+            .as_list()
+            .unwrap();
+        assert_eq!(
+            items.iter().filter_map(|v| v.as_i32()).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        let stack = objs[1].value.as_ref().unwrap().as_stack().unwrap();
+        assert_eq!(
+            stack.iter().filter_map(|v| v.as_i32()).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+
+        let queue = objs[2].value.as_ref().unwrap().as_queue().unwrap();
+        assert_eq!(
+            queue.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>(),
+            vec!["x", "y"]
+        );
+
+        let reparsed = parse_cli_xml(&objs[1].value.as_ref().unwrap().to_cli_xml()).unwrap();
+        assert_eq!(reparsed[0].type_names[0], "System.Collections.Stack");
+        assert_eq!(
+            reparsed[0]
+                .value
+                .as_ref()
+                .unwrap()
+                .as_stack()
+                .map(|s| s.len()),
+            Some(2)
+        );
+    }
+
     #[test]
     fn test_cli_xml_complex() {
         // Get-VM IT-HELP-DVLS | Select-Object -Property VMId, VMName, State, Uptime, Status, Version
@@ -269,11 +1457,11 @@ mod pwsh {
     </Obj>
 </Objs>"#;
 
-        let objs: Vec<CliObject> = parse_cli_xml(vm_xml);
+        let objs: Vec<CliObject> = parse_cli_xml(vm_xml).unwrap();
 
         let vm_obj = objs.get(0).unwrap();
 
-        let vmid_prop = vm_obj.values.get(0).unwrap();
+        let vmid_prop = vm_obj.extended_properties.get(0).unwrap();
         assert!(vmid_prop.is_guid());
         assert_eq!(vmid_prop.get_name(), Some("VMId"));
         assert_eq!(
@@ -283,11 +1471,44 @@ mod pwsh {
                 .as_ref()
         );
 
-        let vmname_prop = vm_obj.values.get(1).unwrap();
+        let vmname_prop = vm_obj.extended_properties.get(1).unwrap();
         assert!(vmname_prop.is_string());
         assert_eq!(vmname_prop.get_name(), Some("VMName"));
         assert_eq!(vmname_prop.as_str(), Some("IT-HELP-DVLS"));
 
+        // the nested State object is a property, followed by the remaining properties
+        assert_eq!(objs.len(), 1);
+        assert_eq!(vm_obj.extended_properties.len(), 6);
+        let state_prop = vm_obj.extended_properties.get(2).unwrap();
+        assert_eq!(state_prop.get_name(), Some("State"));
+        let state_obj = state_prop.as_object().unwrap();
+        assert_eq!(
+            state_obj.type_names.first().map(|s| s.as_str()),
+            Some("Microsoft.HyperV.PowerShell.VMState")
+        );
+        assert_eq!(state_obj.string_repr.as_deref(), Some("Off"));
+        assert_eq!(state_obj.value.as_deref().and_then(|v| v.as_i32()), Some(3));
+        assert_eq!(
+            vm_obj.extended_properties.get(3).unwrap().get_name(),
+            Some("Uptime")
+        );
+        assert_eq!(
+            vm_obj.extended_properties.get(5).unwrap().as_str(),
+            Some("10.0")
+        );
+
+        assert_eq!(vm_obj.get_str("vmname"), Ok("IT-HELP-DVLS"));
+        assert_eq!(vm_obj.get_i32("State.Value"), Ok(3));
+        assert_eq!(vm_obj.get_duration("Uptime"), Ok(&Duration::from_secs(0)));
+        assert_eq!(
+            vm_obj.get_i32("Status"),
+            Err(CliPathError::WrongType {
+                path: "Status".to_string(),
+                expected: "a 32-bit integer",
+                found: "a string",
+            })
+        );
+
         let cmd_xml = r#"<Objs Version="1.1.0.1" xmlns="http://schemas.microsoft.com/powershell/2004/04">
   <Obj RefId="0">
     <TN RefId="0">
@@ -397,6 +1618,71 @@ mod pwsh {
 </Objs>
 "#;
 
-        let _objs: Vec<CliObject> = parse_cli_xml(cmd_xml);
+        let objs: Vec<CliObject> = parse_cli_xml(cmd_xml).unwrap();
+        assert_eq!(objs.len(), 1);
+
+        let cmd_obj = objs.first().unwrap();
+        assert_eq!(
+            cmd_obj.string_repr.as_deref(),
+            Some("System.Diagnostics.Process (cmd)")
+        );
+        let names: Vec<&str> = cmd_obj.properties().filter_map(|v| v.get_name()).collect();
+        assert_eq!(names.first(), Some(&"SafeHandle"));
+        assert_eq!(names.last(), Some(&"__NounName"));
+        assert_eq!(names.len(), cmd_obj.properties().count());
+        assert_eq!(
+            cmd_obj
+                .extended_properties
+                .first()
+                .and_then(|v| v.get_name()),
+            Some("Name")
+        );
+        assert!(cmd_obj.get("Name").is_some());
+
+        let modules_obj = cmd_obj.get("Modules").and_then(|v| v.as_object()).unwrap();
+        assert_eq!(
+            modules_obj.type_names.first().map(|s| s.as_str()),
+            Some("System.Diagnostics.ProcessModuleCollection")
+        );
+        assert!(modules_obj.adapted_properties.is_empty());
+        let modules = modules_obj.value.as_ref().unwrap().as_enumerable().unwrap();
+        assert_eq!(modules.len(), 12);
+        assert_eq!(
+            modules[0].as_str(),
+            Some("System.Diagnostics.ProcessModule (cmd.exe)")
+        );
+
+        let process_name = cmd_obj.get("ProcessName");
+        assert_eq!(process_name.and_then(|v| v.as_str()), Some("cmd"));
+
+        assert_eq!(
+            cmd_obj.get_str("Modules[0]"),
+            Ok("System.Diagnostics.ProcessModule (cmd.exe)")
+        );
+        assert_eq!(cmd_obj.get_collection("modules").map(|m| m.len()), Ok(12));
+        assert_eq!(
+            cmd_obj
+                .query("Modules[0].FileName")
+                .unwrap_err()
+                .to_string(),
+            "`Modules[0]` is a string, expected an object"
+        );
+        assert_eq!(
+            cmd_obj.query("Modules[12]").unwrap_err(),
+            CliPathError::IndexOutOfRange {
+                path: "Modules[12]".to_string(),
+                len: 12
+            }
+        );
+        assert_eq!(
+            cmd_obj.query("Modules.Count").unwrap_err(),
+            CliPathError::MissingProperty {
+                path: "Modules.Count".to_string()
+            }
+        );
+        assert_eq!(
+            cmd_obj.query("Modules[x]").unwrap_err(),
+            CliPathError::InvalidPath("Modules[x]".to_string())
+        );
     }
 }
@@ -1,5 +1,5 @@
 use time::format_description::well_known::Iso8601;
-use time::macros::{date, time, format_description};
+use time::macros::{date, format_description, time};
 use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -7,16 +7,17 @@ pub struct DateTime {
     inner: OffsetDateTime,
 }
 
-#[allow(dead_code)] 
+#[allow(dead_code)]
 impl DateTime {
     pub fn parse(input: &str) -> Option<Self> {
         // Try parsing as OffsetDateTime (with timezone)
         if let Ok(inner) = OffsetDateTime::parse(input, &Iso8601::DEFAULT) {
             return Some(Self { inner });
         }
-        
+
         // If parsing without timezone, assume UTC (Offset +00:00)
-        let format = format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond]");
+        let format =
+            format_description!("[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond]");
         if let Ok(primitive) = PrimitiveDateTime::parse(input, &format) {
             let inner = primitive.assume_offset(UtcOffset::UTC);
             return Some(Self { inner });
@@ -44,8 +45,24 @@ pub fn parse_iso8601_duration(input: &str) -> Option<std::time::Duration> {
         .map(|x| x.to_std())
 }
 
+pub fn format_iso8601_duration(duration: &std::time::Duration) -> String {
+    // .NET TimeSpan precision is 100 nanoseconds (one tick)
+    let ticks = duration.subsec_nanos() / 100;
+    if ticks == 0 {
+        format!("PT{}S", duration.as_secs())
+    } else {
+        let fraction = format!("{:07}", ticks);
+        format!(
+            "PT{}.{}S",
+            duration.as_secs(),
+            fraction.trim_end_matches('0')
+        )
+    }
+}
+
 #[cfg(test)]
 mod pwsh {
+    use crate::time::format_iso8601_duration;
     use crate::time::parse_iso8601_duration;
     use crate::time::DateTime;
 
@@ -64,10 +81,25 @@ mod pwsh {
         );
     }
 
+    #[test]
+    fn format_duration() {
+        assert_eq!(
+            format_iso8601_duration(&std::time::Duration::new(0, 0)),
+            "PT0S".to_string()
+        );
+
+        assert_eq!(
+            format_iso8601_duration(&std::time::Duration::new(9, 26902600)),
+            "PT9.0269026S".to_string()
+        );
+    }
+
     #[test]
     fn parse_datetime() {
         assert_eq!(
-            DateTime::parse("2024-09-17T10:55:56.7639518-04:00").unwrap().format(),
+            DateTime::parse("2024-09-17T10:55:56.7639518-04:00")
+                .unwrap()
+                .format(),
             "2024-09-17T10:55:56.7639518-04:00".to_string()
         )
     }